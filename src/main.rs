@@ -2,18 +2,41 @@
 
 use image::GenericImageView;
 use indexmap::{IndexMap as HashMap, IndexSet};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, rc::Rc};
 
 use quicksilver::{
     geom::{Rectangle, Vector},
     graphics::{Color, Graphics, Image, PixelFormat},
     input::{Event, GamepadAxis, GamepadButton, Key},
-    run, Input, Result, Settings, Timer, Window,
+    run,
+    saving::Location,
+    Input, Result, Settings, Timer, Window,
 };
 
 const SPRITES: &[u8] = include_bytes!("../static/monochrome_transparent_packed.png");
-const SPRITE_WIDTH: usize = 16;
-const MAX_SCALE: usize = 180;
+const MAX_SCALE: f32 = 180.0;
+const MIN_SCALE: f32 = 0.25;
 const SCALE_CHANGE_TIMEOUT: f32 = 1.0;
+const SCALE_TWEEN_DURATION: f32 = 0.5;
+const BLOCKED_FEEDBACK_TIME: f32 = 0.5;
+const DEFAULT_HARDNESS: f32 = 1.0;
+const PARTICLE_BUDGET: usize = 300;
+// Beyond this many screen widths from the camera debris gets merged into bigger chunks
+const PARTICLE_LOD_DISTANCE: f32 = 0.5;
+// Steepest slope in degrees a pile of rubble will hold before grains slide off
+const SAND_REPOSE_ANGLE: f32 = 45.0;
+const SAND_REGIONS_PER_STEP: usize = 32;
+// Seconds rubble stays passable after the last time anything was inside it
+const RUBBLE_DECAY_TIME: f32 = 1.0;
+// Stops a level script that loops forever from hanging the game
+const SCRIPT_MAX_OPERATIONS: u64 = 100_000;
+// Anything further down than this has fallen out of the level
+const WORLD_FLOOR: f32 = 30000.0;
+// Seconds the player can spend with their head under water
+const BREATH: f32 = 3.0;
+const SCREEN_WIDTH: f32 = 1920.0;
+const SCREEN_HEIGHT: f32 = 1080.0;
 const TILE_SIZE: u32 = 256;
 
 const COLLISION_MAP_LEAF_SIZE: usize = 64;
@@ -41,7 +64,7 @@ fn main() {
     run(
         Settings {
             title: "Pixel Game!",
-            size: Vector::new(1920.0, 1080.0),
+            size: Vector::new(SCREEN_WIDTH, SCREEN_HEIGHT),
             fullscreen: true,
             ..Settings::default()
         },
@@ -49,69 +72,328 @@ fn main() {
     );
 }
 
-fn extract_sprite(
-    src: &image::DynamicImage,
-    x: usize,
-    y: usize,
-) -> [bool; SPRITE_WIDTH * SPRITE_WIDTH] {
-    let mut pixels = vec![0; SPRITE_WIDTH * SPRITE_WIDTH * 4];
-    let mut collider = [false; SPRITE_WIDTH * SPRITE_WIDTH];
-    let x = x * SPRITE_WIDTH;
-    let y = y * SPRITE_WIDTH;
-    for dx in 0..SPRITE_WIDTH {
-        for dy in 0..SPRITE_WIDTH {
-            let i = dx * 4 + dy * 4 * SPRITE_WIDTH;
-            let p = src.get_pixel((x + dx) as u32, (y + dy) as u32);
-            pixels[i] = p.0[0];
-            pixels[i + 1] = p.0[1];
-            pixels[i + 2] = p.0[2];
-            pixels[i + 3] = p.0[3];
+fn extract_sprite(src: &image::DynamicImage, x: u32, y: u32, width: u32, height: u32) -> Mask {
+    let mut collider = Mask::new(width as usize, height as usize);
+    let mut colors = vec![[0; 4]; (width * height) as usize];
+    for dx in 0..width {
+        for dy in 0..height {
+            let p = src.get_pixel(x + dx, y + dy);
             if p.0[3] > 0 {
-                collider[dx + dy * SPRITE_WIDTH] = true;
+                collider.set(dx as usize, dy as usize, true);
+                colors[(dx + dy * width) as usize] = p.0;
             }
         }
     }
+    collider.colors = Some(colors);
     collider
 }
 
+// Which pixels of a sprite are solid, in sprite pixels before scaling. Full
+// color sprites also keep the RGBA they were loaded with
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Mask {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+    colors: Option<Vec<[u8; 4]>>,
+}
+
+impl Mask {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![false; width * height],
+            colors: None,
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.pixels[x + y * self.width]
+    }
+
+    fn set(&mut self, x: usize, y: usize, solid: bool) {
+        self.pixels[x + y * self.width] = solid;
+    }
+
+    fn color(&self, x: usize, y: usize) -> Option<[u8; 4]> {
+        self.colors.as_ref().map(|c| c[x + y * self.width])
+    }
+
+    fn set_color(&mut self, x: usize, y: usize, color: Option<[u8; 4]>) {
+        if let Some(color) = color {
+            let len = self.width * self.height;
+            self.colors.get_or_insert_with(|| vec![[0; 4]; len])[x + y * self.width] = color;
+        }
+    }
+
+    fn flat(mut self) -> Self {
+        self.colors = None;
+        self
+    }
+
+    fn tinted(mut self, tint: Color) -> Self {
+        if let Some(colors) = self.colors.as_mut() {
+            for c in colors.iter_mut() {
+                c[0] = (c[0] as f32 * tint.r) as u8;
+                c[1] = (c[1] as f32 * tint.g) as u8;
+                c[2] = (c[2] as f32 * tint.b) as u8;
+                c[3] = (c[3] as f32 * tint.a) as u8;
+            }
+        }
+        self
+    }
+}
+
+struct TileSheet {
+    first_gid: u32,
+    tile_count: u32,
+    columns: u32,
+    tile_width: u32,
+    tile_height: u32,
+    margin: u32,
+    spacing: u32,
+    image: image::DynamicImage,
+}
+
+// Every tileset in the map, so gids can be resolved to masks
+struct Tilesets {
+    sheets: Vec<TileSheet>,
+}
+
+impl Tilesets {
+    async fn load(map: &tiled::Map) -> Self {
+        let mut sheets = vec![];
+        for tileset in &map.tilesets {
+            // Image collection tilesets have no single sheet to cut tiles from, so
+            // anything using one of their gids is left out of the level
+            let source = match tileset.images.first() {
+                Some(image) => &image.source,
+                None => continue,
+            };
+            let image = if source.ends_with("monochrome_transparent_packed.png") {
+                image::load(std::io::Cursor::new(SPRITES), image::ImageFormat::Png).unwrap()
+            } else {
+                let data = quicksilver::load_file(source)
+                    .await
+                    .expect("The tileset image was not found!");
+                image::load_from_memory(&data).unwrap()
+            };
+            let columns = (image.width() - tileset.margin * 2 + tileset.spacing)
+                / (tileset.tile_width + tileset.spacing);
+            let rows = (image.height() - tileset.margin * 2 + tileset.spacing)
+                / (tileset.tile_height + tileset.spacing);
+            sheets.push(TileSheet {
+                first_gid: tileset.first_gid,
+                tile_count: columns * rows,
+                columns,
+                tile_width: tileset.tile_width,
+                tile_height: tileset.tile_height,
+                margin: tileset.margin,
+                spacing: tileset.spacing,
+                image,
+            });
+        }
+        sheets.sort_by_key(|s| s.first_gid);
+        Self { sheets }
+    }
+
+    fn sheet(&self, gid: u32) -> Option<&TileSheet> {
+        self.sheets
+            .iter()
+            .find(|s| s.first_gid <= gid && gid < s.first_gid + s.tile_count)
+    }
+
+    fn tile_size(&self, gid: u32) -> Option<(u32, u32)> {
+        let sheet = self.sheet(gid)?;
+        Some((sheet.tile_width, sheet.tile_height))
+    }
+
+    fn tile(&self, gid: u32) -> Option<Mask> {
+        let sheet = self.sheet(gid)?;
+        let i = gid - sheet.first_gid;
+        let tx = i % sheet.columns;
+        let ty = i / sheet.columns;
+        Some(extract_sprite(
+            &sheet.image,
+            sheet.margin + tx * (sheet.tile_width + sheet.spacing),
+            sheet.margin + ty * (sheet.tile_height + sheet.spacing),
+            sheet.tile_width,
+            sheet.tile_height,
+        ))
+    }
+}
+
+// The flip bits Tiled stores in the top of a gid, diagonal is applied first
+// and combined with the others gives 90 degree rotations
+#[derive(Copy, Clone, Default)]
+struct TileFlags {
+    horizontal: bool,
+    vertical: bool,
+    diagonal: bool,
+}
+
+impl TileFlags {
+    fn from_gid(gid: u32) -> (Self, u32) {
+        (
+            Self {
+                horizontal: gid & 0x80000000 != 0,
+                vertical: gid & 0x40000000 != 0,
+                diagonal: gid & 0x20000000 != 0,
+            },
+            gid & 0x1fffffff,
+        )
+    }
+
+    fn apply(&self, src: &Mask) -> Mask {
+        let mut dst = if self.diagonal {
+            Mask::new(src.height, src.width)
+        } else {
+            Mask::new(src.width, src.height)
+        };
+        for x in 0..src.width {
+            for y in 0..src.height {
+                let (mut dx, mut dy) = if self.diagonal { (y, x) } else { (x, y) };
+                if self.horizontal {
+                    dx = dst.width - dx - 1;
+                }
+                if self.vertical {
+                    dy = dst.height - dy - 1;
+                }
+                dst.set(dx, dy, src.get(x, y));
+                dst.set_color(dx, dy, src.color(x, y));
+            }
+        }
+        dst
+    }
+}
+
+fn mirror_mask(src: &Mask) -> Mask {
+    TileFlags {
+        horizontal: true,
+        ..TileFlags::default()
+    }
+    .apply(src)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum AnimState {
+    Idle,
+    Walk,
+    Jump,
+    Fall,
+    Drink,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ColliderMode {
+    // Keep the collider the sprite was loaded with
+    Fixed,
+    // Use the first frame of the current animation
+    PerAnimation,
+    PerFrame,
+}
+
+#[derive(Clone)]
+struct Animation {
+    frames: Vec<Mask>,
+    fps: f32,
+}
+
+#[derive(Clone)]
+struct Animator {
+    animations: HashMap<AnimState, Animation>,
+    collider_mode: ColliderMode,
+    state: AnimState,
+    frame: usize,
+    timer: f32,
+}
+
+impl Animator {
+    fn mask(&self) -> Option<&Mask> {
+        self.animations
+            .get(&self.state)
+            .and_then(|a| a.frames.get(self.frame))
+    }
+
+    // Returns true when the displayed frame changed
+    fn step(&mut self, state: AnimState, fps: f32) -> bool {
+        let state = if self.animations.contains_key(&state) {
+            state
+        } else {
+            AnimState::Idle
+        };
+        if state != self.state {
+            self.state = state;
+            self.frame = 0;
+            self.timer = 0.0;
+            return true;
+        }
+        if let Some(animation) = self.animations.get(&self.state) {
+            if animation.frames.len() > 1 && animation.fps > 0.0 {
+                self.timer += 1.0 / fps;
+                if self.timer >= 1.0 / animation.fps {
+                    self.timer -= 1.0 / animation.fps;
+                    self.frame = (self.frame + 1) % animation.frames.len();
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn collider(&self) -> Option<&Mask> {
+        match self.collider_mode {
+            ColliderMode::Fixed => None,
+            ColliderMode::PerAnimation => self
+                .animations
+                .get(&self.state)
+                .and_then(|a| a.frames.first()),
+            ColliderMode::PerFrame => self.mask(),
+        }
+    }
+}
+
+#[derive(Clone)]
 struct Sprite {
     is_player: bool,
-    collider: [bool; SPRITE_WIDTH * SPRITE_WIDTH],
+    collider: Mask,
     loc: Vector,
-    x_scale: u32,
-    y_scale: u32,
+    x_scale: f32,
+    y_scale: f32,
     velocity: Vector,
     ground_contact: bool,
     jumping: bool,
+    vx_slop: f32,
     vy_slop: f32,
     color: Color,
     potion_timer: Option<f32>,
-    pending_potions: Vec<PotionType>,
+    scale_tween: Option<ScaleTween>,
+    blocked_timer: Option<f32>,
+    pending_potions: Vec<Potion>,
+    active_potions: Vec<ActivePotion>,
+    gravity_scale: f32,
+    jump_scale: f32,
     sleep_timer: f32,
     gravity: bool,
+    animator: Option<Animator>,
+    // Sheet art faces right, the collider is kept mirrored while this is set
+    facing_left: bool,
+    // Physics sets this when horizontal movement ran into terrain last step
+    blocked_x: bool,
 }
 
 impl Sprite {
-    fn new(
-        src: &image::DynamicImage,
-        x: usize,
-        y: usize,
-        xx: f32,
-        yy: f32,
-        x_scale: u32,
-        y_scale: u32,
-        color: Color,
-    ) -> Self {
-        let collider = extract_sprite(src, x, y);
-        Sprite::from_collider(collider, xx, yy, x_scale, y_scale, color)
+    fn new(tile: &Mask, xx: f32, yy: f32, x_scale: f32, y_scale: f32, color: Color) -> Self {
+        Sprite::from_collider(tile.clone(), xx, yy, x_scale, y_scale, color)
     }
 
     fn from_collider(
-        collider: [bool; SPRITE_WIDTH * SPRITE_WIDTH],
+        collider: Mask,
         xx: f32,
         yy: f32,
-        x_scale: u32,
-        y_scale: u32,
+        x_scale: f32,
+        y_scale: f32,
         color: Color,
     ) -> Self {
         Self {
@@ -123,32 +405,35 @@ impl Sprite {
             velocity: Vector::new(0.0, 0.0),
             ground_contact: false,
             jumping: false,
+            vx_slop: 0.0,
             vy_slop: 0.0,
             color,
             potion_timer: None,
+            scale_tween: None,
+            blocked_timer: None,
             pending_potions: Vec::new(),
+            active_potions: Vec::new(),
+            gravity_scale: 1.0,
+            jump_scale: 1.0,
             sleep_timer: 0.0,
             gravity: true,
+            animator: None,
+            facing_left: false,
+            blocked_x: false,
         }
     }
 
-    fn maybe_flip(mut self, flip: bool) -> Self {
-        if flip {
-            let mut collider = [false; SPRITE_WIDTH * SPRITE_WIDTH];
-            for x in 0..SPRITE_WIDTH {
-                for y in 0..SPRITE_WIDTH {
-                    let src_i = x + y * SPRITE_WIDTH;
-                    let dst_i = (SPRITE_WIDTH - x - 1) + y * SPRITE_WIDTH;
-                    collider[dst_i] = self.collider[src_i];
-                }
-            }
-            self.collider = collider;
-        }
-
+    fn maybe_flip(mut self, flags: TileFlags) -> Self {
+        self.collider = flags.apply(&self.collider);
+        self.facing_left = flags.horizontal;
         self
     }
 
     fn quarter(self) -> Vec<Self> {
+        if self.collider.width < 2 || self.collider.height < 2 {
+            // Too thin to split, the offsets below would underflow
+            return vec![self];
+        }
         let Self {
             is_player,
             collider,
@@ -158,81 +443,300 @@ impl Sprite {
             velocity,
             ground_contact,
             jumping,
+            vx_slop,
             vy_slop,
             color,
             potion_timer,
+            scale_tween,
+            blocked_timer,
             pending_potions,
+            active_potions,
+            gravity_scale,
+            jump_scale,
             sleep_timer,
             gravity,
+            ..
         } = self;
-        let new_x_scale = x_scale / 2;
-        let new_y_scale = y_scale / 2;
+        let new_x_scale = x_scale / 2.0;
+        let new_y_scale = y_scale / 2.0;
+        let (w, h) = (collider.width, collider.height);
         [
             (0, 0),
-            (SPRITE_WIDTH / 2 - 1, 0),
-            (0, SPRITE_WIDTH / 2 - 1),
-            (SPRITE_WIDTH / 2 - 1, SPRITE_WIDTH / 2 - 1),
+            (w / 2 - 1, 0),
+            (0, h / 2 - 1),
+            (w / 2 - 1, h / 2 - 1),
         ]
         .iter()
         .map(|(dx, dy)| {
-            let mut new_collider = [false; SPRITE_WIDTH * SPRITE_WIDTH];
-            for x in 0..SPRITE_WIDTH {
-                for y in 0..SPRITE_WIDTH {
-                    let src_i = x / 2 + dx + (y / 2 + dy) * SPRITE_WIDTH;
-                    let dst_i = x + y * SPRITE_WIDTH;
-                    new_collider[dst_i] = collider[src_i];
+            let mut new_collider = Mask::new(w, h);
+            for x in 0..w {
+                for y in 0..h {
+                    new_collider.set(x, y, collider.get(x / 2 + dx, y / 2 + dy));
+                    new_collider.set_color(x, y, collider.color(x / 2 + dx, y / 2 + dy));
                 }
             }
             Self {
                 is_player,
                 collider: new_collider,
-                loc: Vector::new(
-                    loc.x + *dx as f32 * x_scale as f32,
-                    loc.y + *dy as f32 * y_scale as f32,
-                ),
+                loc: Vector::new(loc.x + *dx as f32 * x_scale, loc.y + *dy as f32 * y_scale),
                 x_scale: new_x_scale,
                 y_scale: new_y_scale,
                 velocity,
                 ground_contact,
                 jumping,
+                vx_slop,
                 vy_slop,
                 color,
                 potion_timer,
+                scale_tween: scale_tween.clone(),
+                blocked_timer,
                 pending_potions: pending_potions.clone(),
+                active_potions: active_potions.clone(),
+                gravity_scale,
+                jump_scale,
                 sleep_timer,
                 gravity,
+                animator: None,
+                facing_left: false,
+                blocked_x: false,
             }
         })
         .collect()
     }
 
+    // The world pixels covered by sprite pixel (dx, dy) if the sprite were at
+    // loc. Edges are the scaled pixel boundaries rounded down, and every pixel
+    // covers at least one world pixel so tiny sprites never lose collision
+    fn pixel_rect_at(&self, loc: Vector, dx: i32, dy: i32) -> (i32, i32, u32, u32) {
+        let x0 = (dx as f32 * self.x_scale).floor() as i32;
+        let x1 = ((dx + 1) as f32 * self.x_scale).floor() as i32;
+        let y0 = (dy as f32 * self.y_scale).floor() as i32;
+        let y1 = ((dy + 1) as f32 * self.y_scale).floor() as i32;
+        (
+            loc.x as i32 + x0,
+            loc.y as i32 + y0,
+            (x1 - x0).max(1) as u32,
+            (y1 - y0).max(1) as u32,
+        )
+    }
+
+    fn pixel_rect(&self, dx: i32, dy: i32) -> (i32, i32, u32, u32) {
+        self.pixel_rect_at(self.loc, dx, dy)
+    }
+
+    // Whether the world pixel (x, y) is solid if the sprite were at loc
+    fn solid_at(&self, loc: Vector, x: i32, y: i32) -> bool {
+        let rx = (x - loc.x as i32) as f32;
+        let ry = (y - loc.y as i32) as f32;
+        let x_lo = ((rx / self.x_scale).floor() as i32 - 1).max(0);
+        let x_hi = ((rx + 1.0) / self.x_scale).ceil() as i32 + 1;
+        let y_lo = ((ry / self.y_scale).floor() as i32 - 1).max(0);
+        let y_hi = ((ry + 1.0) / self.y_scale).ceil() as i32 + 1;
+        for dx in x_lo..x_hi.min(self.collider.width as i32) {
+            for dy in y_lo..y_hi.min(self.collider.height as i32) {
+                if self.collider.get(dx as usize, dy as usize) {
+                    let (px, py, w, h) = self.pixel_rect_at(loc, dx, dy);
+                    if x >= px && x < px + w as i32 && y >= py && y < py + h as i32 {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // Size in world pixels
+    fn width(&self) -> u32 {
+        let (x, _, w, _) =
+            self.pixel_rect_at(Vector::new(0.0, 0.0), self.collider.width as i32 - 1, 0);
+        (x + w as i32) as u32
+    }
+
+    // Velocity is in sprite pixels so physics already stretches it by x_scale. Walking
+    // should follow leg length instead, stretched sprites stride out and squashed ones shuffle.
+    fn walk_velocity(&self, speed: f32, fps: f32) -> f32 {
+        speed / fps * self.y_scale / self.x_scale
+    }
+
+    // Physics stretches vertical velocity by y_scale, but jumps and falls follow size()
+    // like every other movement curve, so aspect doesn't change how high a sprite jumps
+    fn fall_scale(&self) -> f32 {
+        self.size() / self.y_scale
+    }
+
+    // Also in sprite pixels, so a jump reaches higher the bigger the sprite is
+    fn jump_velocity(&self, profile: &MovementProfile, fps: f32) -> f32 {
+        -profile.jump_impulse.at(self.size()) / fps * self.jump_scale * self.fall_scale()
+    }
+
+    fn size(&self) -> f32 {
+        self.x_scale.max(self.y_scale)
+    }
+
+    // How far the camera has to zoom out to frame this sprite on a wide screen
+    fn framing_scale(&self) -> f32 {
+        (self.x_scale * SCREEN_HEIGHT / SCREEN_WIDTH).max(self.y_scale)
+    }
+
+    fn area(&self) -> vek::geom::Rect<f32, f32> {
+        vek::geom::Rect::new(
+            self.loc.x,
+            self.loc.y,
+            self.width() as f32,
+            self.height() as f32,
+        )
+    }
+
+    fn height(&self) -> u32 {
+        let (_, y, _, h) =
+            self.pixel_rect_at(Vector::new(0.0, 0.0), 0, self.collider.height as i32 - 1);
+        (y + h as i32) as u32
+    }
+
+    fn resize(&mut self, x_scale: f32, y_scale: f32, anchor: ScaleAnchor) {
+        let dw = self.collider.width as f32 * (x_scale - self.x_scale);
+        let dh = self.collider.height as f32 * (y_scale - self.y_scale);
+        self.x_scale = x_scale;
+        self.y_scale = y_scale;
+        self.loc.x -= dw / 2.0;
+        match anchor {
+            ScaleAnchor::Feet => self.loc.y -= dh,
+            ScaleAnchor::Center => self.loc.y -= dh / 2.0,
+            ScaleAnchor::Head => (),
+        }
+    }
+
+    fn queue_potion(&mut self, potion: Potion) {
+        let timer = self.potion_timer.get_or_insert(SCALE_CHANGE_TIMEOUT);
+        if *timer <= 0.0 {
+            *timer = SCALE_CHANGE_TIMEOUT;
+        }
+        self.pending_potions.push(potion);
+    }
+
+    // The scale with every timed potion that's still applied taken back off
+    fn base_scale(&self) -> (f32, f32) {
+        self.active_potions
+            .iter()
+            .filter(|active| active.applied)
+            .fold(
+                (self.x_scale, self.y_scale),
+                |(x, y), active| match active.revert {
+                    PotionType::Relative(dx, dy) => (x + dx, y + dy),
+                    _ => (x, y),
+                },
+            )
+    }
+
+    // Start the timed scale potions of a tween from `from` towards `target` that ended
+    // at `to`, each reverting its share of the change that actually happened
+    fn activate_scale_potions(
+        &mut self,
+        timed: Vec<(Potion, (f32, f32))>,
+        from: (f32, f32),
+        target: (f32, f32),
+        to: (f32, f32),
+    ) {
+        let share = |from: f32, target: f32, to: f32| {
+            if (target - from).abs() < f32::EPSILON {
+                1.0
+            } else {
+                (to - from) / (target - from)
+            }
+        };
+        let share_x = share(from.0, target.0, to.0);
+        let share_y = share(from.1, target.1, to.1);
+        for (potion, (dx, dy)) in timed {
+            self.active_potions.push(ActivePotion {
+                potion,
+                revert: PotionType::Relative(-dx * share_x, -dy * share_y),
+                elapsed: 0.0,
+                phase: 0.0,
+                applied: true,
+            });
+        }
+    }
+
+    fn step_active_potions(&mut self, fps: f32) {
+        let mut queued = vec![];
+        for active in &mut self.active_potions {
+            active.elapsed += 1.0 / fps;
+            if let Some(period) = active.potion.period {
+                active.phase += 1.0 / fps;
+                if active.phase >= period {
+                    active.phase -= period;
+                    active.applied = !active.applied;
+                    if active.applied {
+                        queued.push(active.potion.once(active.potion.effect));
+                    } else {
+                        queued.push(active.potion.once(active.revert));
+                    }
+                }
+            }
+            if let Some(duration) = active.potion.duration {
+                if active.elapsed >= duration && active.applied {
+                    active.applied = false;
+                    queued.push(active.potion.once(active.revert));
+                }
+            }
+        }
+        self.active_potions.retain(|a| match a.potion.duration {
+            Some(duration) => a.elapsed < duration,
+            None => true,
+        });
+        for potion in queued {
+            self.queue_potion(potion);
+        }
+    }
+
     fn overlap(&self, other: &Sprite) -> bool {
+        self.overlap_at(self.loc, other)
+    }
+
+    fn overlap_at(&self, loc: Vector, other: &Sprite) -> bool {
         let a = vek::geom::Rect::new(
-            self.loc.x as i32,
-            self.loc.y as i32,
-            SPRITE_WIDTH as i32 * self.x_scale as i32,
-            SPRITE_WIDTH as i32 * self.y_scale as i32,
+            loc.x as i32,
+            loc.y as i32,
+            self.width() as i32,
+            self.height() as i32,
         );
         let b = vek::geom::Rect::new(
             other.loc.x as i32,
             other.loc.y as i32,
-            SPRITE_WIDTH as i32 * other.x_scale as i32,
-            SPRITE_WIDTH as i32 * other.y_scale as i32,
+            other.width() as i32,
+            other.height() as i32,
         );
         if a.collides_with_rect(b) {
             let c = a.intersection(b);
             for x in c.x..c.x + c.w {
                 for y in c.y..c.y + c.h {
-                    let dx = (x as i32 - self.loc.x as i32) / self.x_scale as i32;
-                    let dy = (y as i32 - self.loc.y as i32) / self.y_scale as i32;
-                    let ai = dx as usize + dy as usize * SPRITE_WIDTH;
-                    if self.collider[ai] {
-                        let dx = (x as i32 - other.loc.x as i32) / other.x_scale as i32;
-                        let dy = (y as i32 - other.loc.y as i32) / other.y_scale as i32;
-                        let bi = dx as usize + dy as usize * SPRITE_WIDTH;
-                        if other.collider[bi] {
-                            return true;
-                        }
+                    if self.solid_at(loc, x, y) && other.solid_at(other.loc, x, y) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    fn check_rect(&self, x: i32, y: i32, width: u32, height: u32) -> bool {
+        let bounds = vek::geom::Rect::new(
+            self.loc.x as i32,
+            self.loc.y as i32,
+            self.width() as i32,
+            self.height() as i32,
+        );
+        let r = vek::geom::Rect::new(x, y, width as i32, height as i32);
+        if !bounds.collides_with_rect(r) {
+            return false;
+        }
+        for dx in 0..self.collider.width {
+            for dy in 0..self.collider.height {
+                if self.collider.get(dx, dy) {
+                    let (px, py, w, h) = self.pixel_rect(dx as i32, dy as i32);
+                    let p = vek::geom::Rect::new(px, py, w as i32, h as i32);
+                    if p.collides_with_rect(r) {
+                        return true;
                     }
                 }
             }
@@ -241,9 +745,18 @@ impl Sprite {
     }
 
     fn image(&self, gfx: &Graphics) -> Image {
-        let mut pixels = [0; SPRITE_WIDTH * SPRITE_WIDTH * 4];
-        for (i, src) in self.collider.iter().enumerate() {
-            if *src {
+        let mask = match self.animator.as_ref().and_then(|a| a.mask()) {
+            Some(mask) if self.facing_left => mirror_mask(mask),
+            Some(mask) => mask.clone(),
+            None => self.collider.clone(),
+        };
+        let mut pixels = vec![0; mask.width * mask.height * 4];
+        for (i, src) in mask.pixels.iter().enumerate() {
+            if let Some(colors) = &mask.colors {
+                if *src {
+                    pixels[i * 4..i * 4 + 4].copy_from_slice(&colors[i]);
+                }
+            } else if *src {
                 pixels[i * 4] = (self.color.r * 255.0).clamp(0.0, 255.0) as u8;
                 pixels[i * 4 + 1] = (self.color.g * 255.0).clamp(0.0, 255.0) as u8;
                 pixels[i * 4 + 2] = (self.color.b * 255.0).clamp(0.0, 255.0) as u8;
@@ -253,8 +766,8 @@ impl Sprite {
         let image = Image::from_raw(
             gfx,
             Some(&pixels),
-            SPRITE_WIDTH as u32,
-            SPRITE_WIDTH as u32,
+            mask.width as u32,
+            mask.height as u32,
             PixelFormat::RGBA,
         )
         .unwrap();
@@ -380,13 +893,11 @@ impl CollisionTree {
     }
 
     fn add_sprite(&mut self, sprite: &Sprite) {
-        for x in 0..SPRITE_WIDTH {
-            for y in 0..SPRITE_WIDTH {
-                let i = x + y * SPRITE_WIDTH;
-                if sprite.collider[i] {
-                    let rx = x as i32 * sprite.x_scale as i32 + sprite.loc.x as i32;
-                    let ry = y as i32 * sprite.y_scale as i32 + sprite.loc.y as i32;
-                    if let Ok(x) = self.insert_rect(rx, ry, sprite.x_scale, sprite.y_scale) {
+        for x in 0..sprite.collider.width {
+            for y in 0..sprite.collider.height {
+                if sprite.collider.get(x, y) {
+                    let (rx, ry, w, h) = sprite.pixel_rect(x as i32, y as i32);
+                    if let Ok(x) = self.insert_rect(rx, ry, w, h) {
                         if x > 0 {}
                     }
                 }
@@ -395,13 +906,11 @@ impl CollisionTree {
     }
 
     fn clear_sprite(&mut self, sprite: Sprite) {
-        for x in 0..SPRITE_WIDTH {
-            for y in 0..SPRITE_WIDTH {
-                let i = x + y * SPRITE_WIDTH;
-                if sprite.collider[i] {
-                    let rx = x as i32 * sprite.x_scale as i32 + sprite.loc.x as i32;
-                    let ry = y as i32 * sprite.y_scale as i32 + sprite.loc.y as i32;
-                    self.remove_rect(rx, ry, sprite.x_scale, sprite.y_scale);
+        for x in 0..sprite.collider.width {
+            for y in 0..sprite.collider.height {
+                if sprite.collider.get(x, y) {
+                    let (rx, ry, w, h) = sprite.pixel_rect(x as i32, y as i32);
+                    self.remove_rect(rx, ry, w, h);
                 }
             }
         }
@@ -657,594 +1166,3028 @@ impl CollisionTree {
     }
 }
 
+// How small and big sprites are allowed to get, per axis
 #[derive(Copy, Clone)]
-enum PotionType {
-    Relative(i32, i32),
-    Absolute(Option<i32>, Option<i32>),
-}
-struct Scene {
-    sprites: HashMap<usize, Sprite>,
-    sprite_cache: HashMap<usize, Image>,
-    potions: Vec<(usize, PotionType, bool)>,
-    characters: Vec<usize>,
-    particles: Vec<usize>,
-    collectables: Vec<usize>,
-    collected: HashMap<usize, Sprite>,
-    collision_map: CollisionTree,
-    rubble_map: CollisionTree,
-    next_id: usize,
-    foreground_map: CollisionTree,
-    background_map: CollisionTree,
-    tile_cache: HashMap<
-        (i32, i32),
-        (
-            (Option<Vec<u8>>, Option<Image>),
-            (Option<Vec<u8>>, Option<Image>),
-            (Option<Vec<u8>>, Option<Image>),
-        ),
-    >,
-    tile_queue: IndexSet<(u32, i32, i32)>,
-    score: u32,
-    final_potion_triggered: bool,
-    end_sequence_triggered: bool,
-    done: bool,
+struct ScaleLimits {
+    min: (f32, f32),
+    max: (f32, f32),
 }
 
-impl Scene {
-    fn new() -> Self {
-        let world_min = -10000;
-        let world_width = 40000;
-        let mut tile_cache = HashMap::default();
-        for x in world_min / TILE_SIZE as i32..(world_min + world_width) / TILE_SIZE as i32 {
-            for y in world_min / TILE_SIZE as i32..(world_min + world_width) / TILE_SIZE as i32 {
-                tile_cache.insert((x, y), ((None, None), (None, None), (None, None)));
-            }
-        }
+impl Default for ScaleLimits {
+    fn default() -> Self {
         Self {
-            sprites: HashMap::default(),
-            sprite_cache: HashMap::default(),
-            potions: vec![],
-            characters: vec![],
-            particles: vec![],
-            collectables: vec![],
-            collected: Default::default(),
-            collision_map: CollisionTree::new(
-                world_min,
-                world_min,
-                world_width as u32,
-                world_width as u32,
-            ),
-            rubble_map: CollisionTree::new(
-                world_min,
-                world_min,
-                world_width as u32,
-                world_width as u32,
-            ),
-            next_id: 0,
-            tile_cache,
-            foreground_map: CollisionTree::new(
-                world_min,
-                world_min,
-                world_width as u32,
-                world_width as u32,
-            ),
-            background_map: CollisionTree::new(
-                world_min,
-                world_min,
-                world_width as u32,
-                world_width as u32,
-            ),
-            tile_queue: IndexSet::default(),
-            score: 0,
-            final_potion_triggered: false,
-            end_sequence_triggered: false,
-            done: false,
+            min: (MIN_SCALE, MIN_SCALE),
+            max: (MAX_SCALE, MAX_SCALE),
         }
     }
+}
 
-    fn add_sprite(&mut self, sprite: Sprite) -> usize {
-        let id = self.next_id;
-        self.next_id += 1;
-        self.sprites.insert(id, sprite);
-        id
+impl ScaleLimits {
+    fn clamp(&self, scale: (f32, f32)) -> (f32, f32) {
+        (
+            scale.0.clamp(self.min.0, self.max.0),
+            scale.1.clamp(self.min.1, self.max.1),
+        )
     }
 
-    fn add_collectable(&mut self, sprite: Sprite) -> usize {
-        let id = self.add_sprite(sprite);
-        self.collectables.push(id);
-        id
+    // Only height counts, a wide but short sprite hasn't reached full size
+    fn at_max(&self, sprite: &Sprite) -> bool {
+        sprite.y_scale >= self.max.1
     }
+}
 
-    fn add_potion(&mut self, sprite: Sprite, potion_type: PotionType, start_end: bool) -> usize {
+#[derive(Copy, Clone, Serialize, Deserialize)]
+enum PotionType {
+    Relative(f32, f32),
+    Absolute(Option<f32>, Option<f32>),
+    Multiplicative(f32, f32),
+    Gravity(f32),
+    Jump(f32),
+    // Taller and thinner by this factor, keeping area, below one squashes
+    Stretch(f32),
+}
+
+// The point on a sprite that stays put while it changes size
+#[derive(Copy, Clone, Serialize, Deserialize)]
+enum ScaleAnchor {
+    Feet,
+    Center,
+    Head,
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+enum DestructionShape {
+    Circle,
+    // Angles are in radians, zero points right and positive y is down
+    Cone { angle: f32, spread: f32 },
+    Footprint,
+    // Depth is in sprite heights below the feet
+    GroundPound { depth: f32 },
+    // The circle, but reaching ten heights up and down through the floor, used at full size
+    Column,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct DestructionProfile {
+    shape: DestructionShape,
+    strength: f32,
+    // How much weaker the edges are than the center, 0.0 for a hard edge
+    falloff: f32,
+}
+
+impl Default for DestructionProfile {
+    fn default() -> Self {
+        Self {
+            shape: DestructionShape::Circle,
+            strength: 1.0,
+            falloff: 0.0,
+        }
+    }
+}
+
+// Cheap deterministic per pixel noise in [0, 1) for ragged destruction edges
+fn cell_noise(x: i32, y: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343) ^ (y as u32).wrapping_mul(0xd816_3841);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 16;
+    (h & 0xffff) as f32 / 65536.0
+}
+
+// What happens when there isn't enough room to grow
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+enum GrowthPolicy {
+    Smash,
+    Block,
+    Squeeze,
+}
+
+#[derive(Clone)]
+struct ScaleTween {
+    from: (f32, f32),
+    to: (f32, f32),
+    // Where the potions would have taken the sprite before it was squeezed
+    planned: (f32, f32),
+    elapsed: f32,
+    duration: f32,
+    anchor: ScaleAnchor,
+    growth: GrowthPolicy,
+    destruction: DestructionProfile,
+    // Timed scale potions and the change each asked for, activated once the tween
+    // ends so their reverts match the scale actually reached
+    timed: Vec<(Potion, (f32, f32))>,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct Potion {
+    effect: PotionType,
+    anchor: ScaleAnchor,
+    growth: GrowthPolicy,
+    destruction: DestructionProfile,
+    // Seconds after the effect lands before it is reverted
+    duration: Option<f32>,
+    // Seconds between toggling the effect off and on again
+    period: Option<f32>,
+}
+
+impl Potion {
+    fn new(effect: PotionType) -> Self {
+        Self {
+            effect,
+            anchor: ScaleAnchor::Feet,
+            growth: GrowthPolicy::Smash,
+            destruction: DestructionProfile::default(),
+            duration: None,
+            period: None,
+        }
+    }
+
+    // A one-shot copy of this potion with a different effect, used for reverting
+    fn once(&self, effect: PotionType) -> Self {
+        Self {
+            effect,
+            duration: None,
+            period: None,
+            ..*self
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ActivePotion {
+    potion: Potion,
+    revert: PotionType,
+    elapsed: f32,
+    phase: f32,
+    applied: bool,
+}
+
+// Something a level can make happen, written in the map as "name args" and
+// separated by semicolons
+#[derive(Clone)]
+enum Action {
+    // Replace the effect of every potion left in the level
+    SetPotions(PotionType),
+    // From the next potion on the player keeps growing by this much, smashing everything
+    Overgrow(f32),
+    Teleport(Vector),
+    // Collectables smaller than scale grow to it and get scattered around center
+    GatherCollected {
+        center: Vector,
+        spread: f32,
+        scale: f32,
+    },
+    // Remove everything but the player and the cards collected so far, including the
+    // level's hazards, triggers and listeners
+    ClearLevel,
+    Camera {
+        target: Vector,
+        zoom: f32,
+    },
+    Respawn,
+    Win,
+    Lose,
+    // Give the player a potion without a bottle
+    ApplyPotion(PotionType),
+    // Bring in every object parked under this name
+    Spawn(String),
+    ShowText(String, f32),
+    DestroyRegion {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+    ExitLevel(LevelExit),
+    // Respawn here from now on and save the game
+    Checkpoint,
+    // Call a function in one of the level's scripts
+    Script {
+        script: usize,
+        function: String,
+        this: Option<usize>,
+    },
+}
+
+#[derive(Clone)]
+enum LevelExit {
+    // The one after this in the level manifest
+    Next,
+    Map(String),
+}
+
+// Things that happen in a level which map actions can listen for
+#[derive(Copy, Clone, PartialEq)]
+enum SceneEvent {
+    EnteredZone(usize),
+    ExitedZone(usize),
+    PotionDrunk(usize),
+    CollectablePicked(usize),
+    SpriteDestroyed(usize),
+}
+
+struct Listener {
+    event: SceneEvent,
+    actions: Vec<Action>,
+    once: bool,
+}
+
+fn overlaps(a: &vek::geom::Rect<f32, f32>, b: &vek::geom::Rect<f32, f32>) -> bool {
+    a.x < b.x + b.w && a.x + a.w > b.x && a.y < b.y + b.h && a.y + a.h > b.y
+}
+
+#[derive(Copy, Clone)]
+enum HazardKind {
+    // Deadly to anything smaller than safe_scale, bigger players just crush them
+    Spikes { safe_scale: f32 },
+    // Slows the player down and drowns them if their head stays under too long
+    Water { breath: f32 },
+    Kill,
+}
+
+struct Hazard {
+    area: vek::geom::Rect<f32, f32>,
+    kind: HazardKind,
+}
+
+// A rectangle from the triggers group, raising events as the player crosses it
+struct Trigger {
+    area: vek::geom::Rect<f32, f32>,
+    occupied: bool,
+}
+
+// What a parked sprite was before it left the scene, so spawning can put it back
+enum Role {
+    Plain,
+    Character(Option<Npc>),
+    Collectable,
+    Potion(Potion),
+    Platform(Platform),
+    Bomb(Bomb),
+}
+
+#[derive(Copy, Clone)]
+enum Condition {
+    Score(u32),
+    // Picked up a collectable at least this big
+    CollectableScale(f32),
+    MaxScale,
+    Below(f32),
+}
+
+fn parse_point(src: &str) -> Option<Vector> {
+    let mut parts = src.split(',').map(|p| p.trim().parse::<f32>());
+    if let (Some(Ok(x)), Some(Ok(y))) = (parts.next(), parts.next()) {
+        Some(Vector::new(x, y))
+    } else {
+        None
+    }
+}
+
+fn parse_actions(src: &str) -> Vec<Action> {
+    src.split(';')
+        .filter_map(|action| {
+            let mut args = action.split_whitespace();
+            let name = args.next()?;
+            let args: Vec<&str> = args.collect();
+            let float = |i: usize| args.get(i).and_then(|a| a.parse::<f32>().ok());
+            let point = |i: usize| args.get(i).and_then(|a| parse_point(a));
+            Some(match name {
+                "set_potions" => {
+                    let delta = point(0)?;
+                    Action::SetPotions(PotionType::Relative(delta.x, delta.y))
+                }
+                "overgrow" => Action::Overgrow(float(0)?),
+                "teleport" => Action::Teleport(point(0)?),
+                "gather_collected" => Action::GatherCollected {
+                    center: point(0)?,
+                    spread: float(1)?,
+                    scale: float(2)?,
+                },
+                "clear_level" => Action::ClearLevel,
+                "camera" => Action::Camera {
+                    target: point(0)?,
+                    zoom: float(1)?,
+                },
+                "respawn" => Action::Respawn,
+                "win" | "end_level" => Action::Win,
+                "lose" => Action::Lose,
+                "potion" => Action::ApplyPotion(match *args.first()? {
+                    "stretch" => PotionType::Stretch(float(1)?.max(0.01)),
+                    kind => {
+                        let amount = point(1)?;
+                        match kind {
+                            "absolute" => PotionType::Absolute(Some(amount.x), Some(amount.y)),
+                            "multiply" => PotionType::Multiplicative(amount.x, amount.y),
+                            _ => PotionType::Relative(amount.x, amount.y),
+                        }
+                    }
+                }),
+                "spawn" => Action::Spawn(args.first()?.to_string()),
+                "next_level" => Action::ExitLevel(LevelExit::Next),
+                "checkpoint" => Action::Checkpoint,
+                "level" => Action::ExitLevel(LevelExit::Map(args.first()?.to_string())),
+                "text" => Action::ShowText(args.get(1..)?.join(" "), float(0)?),
+                "destroy" => {
+                    let corner = point(0)?;
+                    let size = point(1)?;
+                    Action::DestroyRegion {
+                        x: corner.x as i32,
+                        y: corner.y as i32,
+                        width: size.x as u32,
+                        height: size.y as u32,
+                    }
+                }
+                _ => return None,
+            })
+        })
+        .collect()
+}
+
+fn parse_conditions(src: &str) -> Vec<Condition> {
+    src.split(';')
+        .filter_map(|condition| {
+            let mut args = condition.split_whitespace();
+            let name = args.next()?;
+            let arg = args.next();
+            Some(match name {
+                "score" => Condition::Score(arg?.parse().ok()?),
+                "collectable_scale" => Condition::CollectableScale(arg?.parse().ok()?),
+                "max_scale" => Condition::MaxScale,
+                "below" => Condition::Below(arg?.parse().ok()?),
+                _ => return None,
+            })
+        })
+        .collect()
+}
+
+// A leaf sized block of a terrain map that changed after loading, written over a
+// fresh load when resuming. Blocks are counted in leaves from the world origin.
+#[derive(Clone, Serialize, Deserialize)]
+struct TerrainEdit {
+    layer: u32,
+    x: i32,
+    y: i32,
+    // One bit per pixel, a row per entry
+    rows: Vec<u64>,
+}
+
+// A card picked up on an earlier level, rebuilt without loading that level's map
+#[derive(Serialize, Deserialize)]
+struct SavedCard {
+    collider: Mask,
+    loc: (f32, f32),
+    scale: (f32, f32),
+    color: (f32, f32, f32, f32),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SaveGame {
+    level: usize,
+    loc: (f32, f32),
+    scale: (f32, f32),
+    pending_potions: Vec<Potion>,
+    // Ids of the level's potions and cards that are gone, stable since loading is deterministic
+    consumed: Vec<usize>,
+    picked: Vec<usize>,
+    cards: Vec<SavedCard>,
+    score: u32,
+    largest_collected: f32,
+    terrain: Vec<TerrainEdit>,
+}
+
+const SAVE_NAME: &str = "miz_jam";
+
+// The parts of the scene a script can touch directly, lent to it for the length of a call
+struct ScriptWorld {
+    sprites: HashMap<usize, Sprite>,
+    potions: Vec<(usize, Potion)>,
+    collision_map: CollisionTree,
+    score: u32,
+    scale_limits: ScaleLimits,
+    player: Option<usize>,
+    this: Option<usize>,
+    // Anything that needs the rest of the scene runs once the script returns
+    queued: Vec<Action>,
+}
+
+type SharedWorld = Rc<RefCell<Option<ScriptWorld>>>;
+
+fn lend<T>(world: &SharedWorld, f: impl FnOnce(&mut ScriptWorld) -> T) -> Option<T> {
+    world.borrow_mut().as_mut().map(f)
+}
+
+fn lend_sprite<T>(
+    world: &SharedWorld,
+    id: rhai::INT,
+    f: impl FnOnce(&mut Sprite) -> T,
+) -> Option<T> {
+    lend(world, |w| w.sprites.get_mut(&(id as usize)).map(f)).flatten()
+}
+
+// Scripts are written by hand so let 2 and 2.0 mean the same thing
+fn number(v: rhai::Dynamic) -> f32 {
+    if let Ok(v) = v.as_float() {
+        v as f32
+    } else {
+        v.as_int().unwrap_or(0) as f32
+    }
+}
+
+fn id_to_script(id: Option<usize>) -> rhai::INT {
+    id.map_or(-1, |id| id as rhai::INT)
+}
+
+struct Scripting {
+    engine: rhai::Engine,
+    world: SharedWorld,
+    scripts: Vec<rhai::AST>,
+}
+
+impl Scripting {
+    fn new() -> Self {
+        let world: SharedWorld = Rc::new(RefCell::new(None));
+        let mut engine = rhai::Engine::new();
+        engine.set_max_operations(SCRIPT_MAX_OPERATIONS);
+
+        let w = world.clone();
+        engine.register_fn("this", move || id_to_script(lend(&w, |w| w.this).flatten()));
+        let w = world.clone();
+        engine.register_fn("player", move || {
+            id_to_script(lend(&w, |w| w.player).flatten())
+        });
+        let w = world.clone();
+        engine.register_fn("score", move || {
+            lend(&w, |w| w.score).unwrap_or(0) as rhai::INT
+        });
+        let w = world.clone();
+        engine.register_fn("exists", move |id: rhai::INT| {
+            lend_sprite(&w, id, |_| ()).is_some()
+        });
+        let w = world.clone();
+        engine.register_fn("x", move |id: rhai::INT| {
+            lend_sprite(&w, id, |s| s.loc.x).unwrap_or(0.0) as rhai::FLOAT
+        });
+        let w = world.clone();
+        engine.register_fn("y", move |id: rhai::INT| {
+            lend_sprite(&w, id, |s| s.loc.y).unwrap_or(0.0) as rhai::FLOAT
+        });
+        let w = world.clone();
+        engine.register_fn("scale_x", move |id: rhai::INT| {
+            lend_sprite(&w, id, |s| s.x_scale).unwrap_or(0.0) as rhai::FLOAT
+        });
+        let w = world.clone();
+        engine.register_fn("scale_y", move |id: rhai::INT| {
+            lend_sprite(&w, id, |s| s.y_scale).unwrap_or(0.0) as rhai::FLOAT
+        });
+        let w = world.clone();
+        engine.register_fn(
+            "move_to",
+            move |id: rhai::INT, x: rhai::Dynamic, y: rhai::Dynamic| {
+                lend_sprite(&w, id, |s| {
+                    s.loc = Vector::new(number(x), number(y));
+                    s.velocity = Vector::new(0.0, 0.0);
+                });
+            },
+        );
+        let w = world.clone();
+        engine.register_fn(
+            "set_scale",
+            move |id: rhai::INT, x: rhai::Dynamic, y: rhai::Dynamic| {
+                let limits = lend(&w, |w| w.scale_limits).unwrap_or_default();
+                let (x, y) = limits.clamp((number(x), number(y)));
+                lend_sprite(&w, id, |s| s.resize(x, y, ScaleAnchor::Feet));
+            },
+        );
+        let w = world.clone();
+        engine.register_fn(
+            "give_potion",
+            move |id: rhai::INT, dx: rhai::Dynamic, dy: rhai::Dynamic| {
+                let effect = PotionType::Relative(number(dx), number(dy));
+                lend_sprite(&w, id, |s| s.queue_potion(Potion::new(effect)));
+            },
+        );
+        let w = world.clone();
+        engine.register_fn("potions", move || {
+            lend(&w, |w| {
+                w.potions
+                    .iter()
+                    .map(|(id, _)| rhai::Dynamic::from(*id as rhai::INT))
+                    .collect::<rhai::Array>()
+            })
+            .unwrap_or_default()
+        });
+        let w = world.clone();
+        engine.register_fn(
+            "set_potion",
+            move |id: rhai::INT, dx: rhai::Dynamic, dy: rhai::Dynamic| {
+                let effect = PotionType::Relative(number(dx), number(dy));
+                lend(&w, |w| {
+                    for (potion_id, potion) in &mut w.potions {
+                        if *potion_id == id as usize {
+                            potion.effect = effect;
+                        }
+                    }
+                });
+            },
+        );
+        let w = world.clone();
+        engine.register_fn("solid", move |x: rhai::Dynamic, y: rhai::Dynamic| {
+            let (x, y) = (number(x) as i32, number(y) as i32);
+            lend(&w, |w| w.collision_map.check_point(x, y)).unwrap_or(false)
+        });
+        let w = world.clone();
+        engine.register_fn(
+            "destroy",
+            move |x: rhai::Dynamic,
+                  y: rhai::Dynamic,
+                  width: rhai::Dynamic,
+                  height: rhai::Dynamic| {
+                let action = Action::DestroyRegion {
+                    x: number(x) as i32,
+                    y: number(y) as i32,
+                    width: number(width).max(0.0) as u32,
+                    height: number(height).max(0.0) as u32,
+                };
+                lend(&w, |w| w.queued.push(action));
+            },
+        );
+        let w = world.clone();
+        engine.register_fn("run", move |actions: &str| {
+            let actions = parse_actions(actions);
+            lend(&w, |w| w.queued.extend(actions));
+        });
+
+        Self {
+            engine,
+            world,
+            scripts: vec![],
+        }
+    }
+}
+
+// Debris is just a solid rectangle so it doesn't need a full Sprite
+#[derive(Copy, Clone)]
+struct Particle {
+    loc: Vector,
+    velocity: Vector,
+    width: u32,
+    height: u32,
+    ground_contact: bool,
+    sleep_timer: f32,
+}
+
+struct ParticlePool {
+    particles: Vec<Particle>,
+    budget: usize,
+}
+
+impl ParticlePool {
+    fn new(budget: usize) -> Self {
+        Self {
+            particles: Vec::with_capacity(budget),
+            budget,
+        }
+    }
+
+    fn spawn(&mut self, loc: Vector, velocity: Vector, width: u32, height: u32) -> bool {
+        if self.particles.len() >= self.budget {
+            return false;
+        }
+        self.particles.push(Particle {
+            loc,
+            velocity,
+            width,
+            height,
+            ground_contact: false,
+            sleep_timer: 0.0,
+        });
+        true
+    }
+
+    fn clear(&mut self) {
+        self.particles.clear();
+    }
+
+    // Merge groups of four same sized particles that are far from the camera
+    // into one particle twice as big, which covers the same area
+    fn merge_distant(&mut self, camera: Vector, distance: f32) {
+        let mut buckets: HashMap<(u32, u32, i32, i32), Vec<usize>> = HashMap::default();
+        for (i, p) in self.particles.iter().enumerate() {
+            if camera.distance(p.loc) > distance {
+                let key = (
+                    p.width,
+                    p.height,
+                    (p.loc.x / (p.width * 2) as f32).floor() as i32,
+                    (p.loc.y / (p.height * 2) as f32).floor() as i32,
+                );
+                buckets.entry(key).or_default().push(i);
+            }
+        }
+        let mut merged = vec![];
+        let mut to_remove: IndexSet<usize> = IndexSet::default();
+        for ((width, height, _, _), members) in buckets {
+            for group in members.chunks_exact(4) {
+                let mut loc = Vector::new(0.0, 0.0);
+                let mut velocity = Vector::new(0.0, 0.0);
+                for i in group {
+                    loc += self.particles[*i].loc / 4.0;
+                    velocity += self.particles[*i].velocity / 4.0;
+                    to_remove.insert(*i);
+                }
+                merged.push(Particle {
+                    loc,
+                    velocity: velocity / 2.0,
+                    width: width * 2,
+                    height: height * 2,
+                    ground_contact: false,
+                    sleep_timer: 0.0,
+                });
+            }
+        }
+        if to_remove.is_empty() {
+            return;
+        }
+        let mut i = 0;
+        self.particles.retain(|_| {
+            i += 1;
+            !to_remove.contains(&(i - 1))
+        });
+        self.particles.extend(merged);
+    }
+}
+
+struct Bomb {
+    // Seconds from being lit to exploding, None explodes on contact
+    fuse: Option<f32>,
+    lit: bool,
+    contact: bool,
+    force: f32,
+    profile: DestructionProfile,
+}
+
+// A movement parameter and how it changes with size. The curve is (scale, multiplier)
+// points, interpolated on log scale since scales run from fractions up to hundreds.
+#[derive(Clone, Deserialize)]
+struct Tunable {
+    base: f32,
+    #[serde(default)]
+    curve: Vec<(f32, f32)>,
+}
+
+impl Tunable {
+    fn new(base: f32) -> Self {
+        Self {
+            base,
+            curve: vec![],
+        }
+    }
+
+    fn multiplier(&self, scale: f32) -> f32 {
+        let (first, last) = match (self.curve.first(), self.curve.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 1.0,
+        };
+        if scale <= first.0 {
+            return first.1;
+        }
+        if scale >= last.0 {
+            return last.1;
+        }
+        for pair in self.curve.windows(2) {
+            let ((s0, m0), (s1, m1)) = (pair[0], pair[1]);
+            if scale <= s1 {
+                let t = (scale.ln() - s0.ln()) / (s1.ln() - s0.ln()).max(f32::EPSILON);
+                return m0 + (m1 - m0) * t;
+            }
+        }
+        last.1
+    }
+
+    fn at(&self, scale: f32) -> f32 {
+        self.base * self.multiplier(scale)
+    }
+
+    // The curve can be written in any order, but scales have to be positive for the log
+    fn validate(&mut self, name: &str) -> std::result::Result<(), String> {
+        if !self.base.is_finite() {
+            return Err(format!("{} has a bad base", name));
+        }
+        for (scale, multiplier) in &self.curve {
+            if *scale <= 0.0 || !scale.is_finite() || !multiplier.is_finite() {
+                return Err(format!(
+                    "{} has a bad curve point ({}, {})",
+                    name, scale, multiplier
+                ));
+            }
+        }
+        self.curve.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        Ok(())
+    }
+}
+
+// How characters move, read from movement.json. Speeds are in sprite pixels per second,
+// so physics scales them up with the sprite before any curve is applied.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+struct MovementProfile {
+    walk_speed: Tunable,
+    run_speed: Tunable,
+    jump_impulse: Tunable,
+    // Upward speed a jump is cut to when the button is let go early
+    jump_release: Tunable,
+    gravity: Tunable,
+    // Horizontal speed lost per second while on the ground
+    friction: Tunable,
+}
+
+impl Default for MovementProfile {
+    fn default() -> Self {
+        Self {
+            walk_speed: Tunable::new(60.0),
+            run_speed: Tunable::new(130.0),
+            jump_impulse: Tunable::new(80.0),
+            jump_release: Tunable::new(120.0),
+            gravity: Tunable::new(3.4),
+            friction: Tunable::new(1.0),
+        }
+    }
+}
+
+impl MovementProfile {
+    fn validate(mut self) -> std::result::Result<Self, String> {
+        let mut tunables = [
+            ("walk_speed", &mut self.walk_speed),
+            ("run_speed", &mut self.run_speed),
+            ("jump_impulse", &mut self.jump_impulse),
+            ("jump_release", &mut self.jump_release),
+            ("gravity", &mut self.gravity),
+            ("friction", &mut self.friction),
+        ];
+        for (name, tunable) in tunables.iter_mut() {
+            tunable.validate(name)?;
+        }
+        Ok(self)
+    }
+}
+
+// A broken file falls back to the defaults, along with why so it can be shown in game
+async fn movement_profile() -> (MovementProfile, Option<String>) {
+    match quicksilver::load_file("movement.json").await {
+        Ok(data) => match serde_json::from_slice::<MovementProfile>(&data)
+            .map_err(|e| e.to_string())
+            .and_then(MovementProfile::validate)
+        {
+            Ok(profile) => (profile, None),
+            Err(e) => (
+                MovementProfile::default(),
+                Some(format!("Bad movement profile: {}", e)),
+            ),
+        },
+        Err(_) => (MovementProfile::default(), None),
+    }
+}
+
+// How many times bigger one character has to be than another for each rule to kick in
+#[derive(Copy, Clone)]
+struct SizeRules {
+    // Landing on the smaller one squashes it
+    stomp: f32,
+    // The smaller one can stand on top and gets carried along
+    ride: f32,
+    // The smaller one passes between legs and through gaps untouched
+    slip: f32,
+    // Walking into the smaller one shoves it aside, below this both hold each other back
+    push: f32,
+}
+
+impl Default for SizeRules {
+    fn default() -> Self {
+        Self {
+            stomp: 3.0,
+            ride: 3.0,
+            slip: 6.0,
+            push: 1.5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Behavior {
+    // Walk back and forth within range of where it started, turning at walls
+    Patrol,
+    Chase,
+    // Run from the player, but only while the player is the bigger one
+    Flee,
+    // Stay put until the player comes within range, then chase
+    Sleep,
+}
+
+struct Npc {
+    behavior: Behavior,
+    speed: f32,
+    range: f32,
+    origin: f32,
+    direction: f32,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum PathMode {
+    Loop,
+    PingPong,
+}
+
+#[derive(Clone)]
+struct Platform {
+    path: Vec<Vector>,
+    speed: f32,
+    mode: PathMode,
+    target: usize,
+    forward: bool,
+}
+
+impl Platform {
+    fn new(path: Vec<Vector>, speed: f32, mode: PathMode) -> Self {
+        Self {
+            target: if path.len() > 1 { 1 } else { 0 },
+            path,
+            speed,
+            mode,
+            forward: true,
+        }
+    }
+
+    fn advance(&mut self) {
+        if self.path.len() < 2 {
+            return;
+        }
+        match self.mode {
+            PathMode::Loop => {
+                self.target = (self.target + 1) % self.path.len();
+            }
+            PathMode::PingPong => {
+                if self.forward && self.target + 1 >= self.path.len() {
+                    self.forward = false;
+                } else if !self.forward && self.target == 0 {
+                    self.forward = true;
+                }
+                if self.forward {
+                    self.target += 1;
+                } else {
+                    self.target -= 1;
+                }
+            }
+        }
+    }
+}
+
+struct Scene {
+    sprites: HashMap<usize, Sprite>,
+    sprite_cache: HashMap<usize, Image>,
+    potions: Vec<(usize, Potion)>,
+    platforms: Vec<(usize, Platform)>,
+    bombs: Vec<(usize, Bomb)>,
+    characters: Vec<usize>,
+    npcs: Vec<(usize, Npc)>,
+    size_rules: SizeRules,
+    movement: MovementProfile,
+    particles: ParticlePool,
+    collectables: Vec<usize>,
+    collected: HashMap<usize, Sprite>,
+    collision_map: CollisionTree,
+    materials: Vec<(vek::geom::Rect<i32, i32>, f32)>,
+    rubble_map: CollisionTree,
+    // Leaf sized regions of the rubble map and how long until they set solid
+    rubble_timers: HashMap<(i32, i32), f32>,
+    falling_sand: bool,
+    sand_repose: f32,
+    // Leaf sized regions of the rubble map that may still have loose grains
+    sand_dirty: IndexSet<(i32, i32)>,
+    sand_tick: u32,
+    next_id: usize,
+    foreground_map: CollisionTree,
+    background_map: CollisionTree,
+    tile_cache: HashMap<
+        (i32, i32),
+        (
+            (Option<Vec<u8>>, Option<Image>),
+            (Option<Vec<u8>>, Option<Image>),
+            (Option<Vec<u8>>, Option<Image>),
+        ),
+    >,
+    tile_queue: IndexSet<(u32, i32, i32)>,
+    // RGBA baked in from full color sprites, by (layer, tile x, tile y)
+    tile_colors: HashMap<(u32, i32, i32), Vec<u8>>,
+    scale_limits: ScaleLimits,
+    // Where the camera goes once the player is as big as the level allows
+    max_scale_camera: Option<Vector>,
+    score: u32,
+    largest_collected: f32,
+    win_conditions: Vec<Condition>,
+    lose_conditions: Vec<Condition>,
+    on_win: Vec<Action>,
+    on_lose: Vec<Action>,
+    spawn: Vector,
+    spawn_scale: (f32, f32),
+    hazards: Vec<Hazard>,
+    world_floor: f32,
+    // How long the player's head has been under water
+    underwater: f32,
+    camera_override: Option<(Vector, f32)>,
+    overgrow_step: f32,
+    triggers: Vec<Trigger>,
+    events: Vec<SceneEvent>,
+    listeners: Vec<Listener>,
+    // Objects kept out of the level until a spawn action names them
+    parked: HashMap<String, Vec<(usize, Sprite, Role)>>,
+    message: Option<(String, f32)>,
+    scripting: Scripting,
+    // Set when the level is over and the game should move on
+    exit: Option<LevelExit>,
+    // Sprite ids below this came from the map rather than an earlier level
+    level_sprites: usize,
+    consumed: Vec<usize>,
+    // Blocks of the terrain and foreground maps that changed since loading, as (layer, x, y)
+    terrain_edits: IndexSet<(u32, i32, i32)>,
+    save_requested: bool,
+    final_potion_triggered: bool,
+    end_sequence_triggered: bool,
+    done: bool,
+}
+
+impl Scene {
+    fn new() -> Self {
+        let world_min = -10000;
+        let world_width = 40000;
+        let mut tile_cache = HashMap::default();
+        for x in world_min / TILE_SIZE as i32..(world_min + world_width) / TILE_SIZE as i32 {
+            for y in world_min / TILE_SIZE as i32..(world_min + world_width) / TILE_SIZE as i32 {
+                tile_cache.insert((x, y), ((None, None), (None, None), (None, None)));
+            }
+        }
+        Self {
+            sprites: HashMap::default(),
+            sprite_cache: HashMap::default(),
+            potions: vec![],
+            platforms: vec![],
+            bombs: vec![],
+            characters: vec![],
+            npcs: vec![],
+            size_rules: SizeRules::default(),
+            movement: MovementProfile::default(),
+            particles: ParticlePool::new(PARTICLE_BUDGET),
+            collectables: vec![],
+            collected: Default::default(),
+            collision_map: CollisionTree::new(
+                world_min,
+                world_min,
+                world_width as u32,
+                world_width as u32,
+            ),
+            materials: vec![],
+            rubble_map: CollisionTree::new(
+                world_min,
+                world_min,
+                world_width as u32,
+                world_width as u32,
+            ),
+            rubble_timers: HashMap::default(),
+            falling_sand: false,
+            sand_repose: SAND_REPOSE_ANGLE.to_radians(),
+            sand_dirty: IndexSet::default(),
+            sand_tick: 0,
+            next_id: 0,
+            tile_cache,
+            foreground_map: CollisionTree::new(
+                world_min,
+                world_min,
+                world_width as u32,
+                world_width as u32,
+            ),
+            background_map: CollisionTree::new(
+                world_min,
+                world_min,
+                world_width as u32,
+                world_width as u32,
+            ),
+            tile_queue: IndexSet::default(),
+            tile_colors: HashMap::default(),
+            scale_limits: ScaleLimits::default(),
+            max_scale_camera: None,
+            score: 0,
+            largest_collected: 0.0,
+            win_conditions: vec![],
+            lose_conditions: vec![],
+            on_win: vec![],
+            on_lose: vec![Action::Respawn],
+            spawn: Vector::new(0.0, 0.0),
+            spawn_scale: (1.0, 1.0),
+            hazards: vec![],
+            world_floor: WORLD_FLOOR,
+            underwater: 0.0,
+            camera_override: None,
+            overgrow_step: 0.0,
+            triggers: vec![],
+            events: vec![],
+            listeners: vec![],
+            parked: HashMap::default(),
+            message: None,
+            scripting: Scripting::new(),
+            exit: None,
+            level_sprites: 0,
+            consumed: vec![],
+            terrain_edits: IndexSet::new(),
+            save_requested: false,
+            final_potion_triggered: false,
+            end_sequence_triggered: false,
+            done: false,
+        }
+    }
+
+    fn add_sprite(&mut self, sprite: Sprite) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sprites.insert(id, sprite);
+        id
+    }
+
+    fn add_collectable(&mut self, sprite: Sprite) -> usize {
+        let id = self.add_sprite(sprite);
+        self.collectables.push(id);
+        id
+    }
+
+    fn add_potion(&mut self, sprite: Sprite, potion: Potion) -> usize {
+        let id = self.add_sprite(sprite);
+        self.potions.push((id, potion));
+        id
+    }
+
+    fn add_platform(&mut self, mut sprite: Sprite, platform: Platform) -> usize {
+        sprite.gravity = false;
+        let id = self.add_sprite(sprite);
+        self.platforms.push((id, platform));
+        id
+    }
+
+    fn add_bomb(&mut self, sprite: Sprite, bomb: Bomb) -> usize {
         let id = self.add_sprite(sprite);
-        self.potions.push((id, potion_type, start_end));
+        self.bombs.push((id, bomb));
         id
     }
 
-    fn add_particle(&mut self, sprite: Sprite) -> usize {
-        let id = self.add_sprite(sprite);
-        self.particles.push(id);
-        id
+    fn add_character(&mut self, sprite: Sprite) -> usize {
+        let id = self.add_sprite(sprite);
+        self.characters.push(id);
+        id
+    }
+
+    fn add_npc(&mut self, sprite: Sprite, npc: Npc) -> usize {
+        let id = self.add_character(sprite);
+        self.npcs.push((id, npc));
+        id
+    }
+
+    fn log_edit(&mut self, layer: u32, x: i32, y: i32, width: u32, height: u32) {
+        let leaf = COLLISION_MAP_LEAF_SIZE as i32;
+        for bx in x.div_euclid(leaf)..=(x + width.max(1) as i32 - 1).div_euclid(leaf) {
+            for by in y.div_euclid(leaf)..=(y + height.max(1) as i32 - 1).div_euclid(leaf) {
+                self.terrain_edits.insert((layer, bx, by));
+            }
+        }
+    }
+
+    fn terrain_map(&self, layer: u32) -> &CollisionTree {
+        if layer == 1 {
+            &self.collision_map
+        } else {
+            &self.foreground_map
+        }
+    }
+
+    fn save(&self, level: usize) -> Option<SaveGame> {
+        let player = &self.sprites[&self.player_id()?];
+        let mut picked = vec![];
+        let mut cards = vec![];
+        for (id, card) in &self.collected {
+            if *id < self.level_sprites {
+                picked.push(*id);
+            } else {
+                cards.push(SavedCard {
+                    collider: card.collider.clone(),
+                    loc: (card.loc.x, card.loc.y),
+                    scale: (card.x_scale, card.y_scale),
+                    color: (card.color.r, card.color.g, card.color.b, card.color.a),
+                });
+            }
+        }
+        Some(SaveGame {
+            level,
+            loc: (self.spawn.x, self.spawn.y),
+            scale: self.spawn_scale,
+            pending_potions: player.pending_potions.clone(),
+            consumed: self.consumed.clone(),
+            picked,
+            cards,
+            score: self.score,
+            largest_collected: self.largest_collected,
+            terrain: self
+                .terrain_edits
+                .iter()
+                .map(|&(layer, x, y)| {
+                    let map = self.terrain_map(layer);
+                    let leaf = COLLISION_MAP_LEAF_SIZE as i32;
+                    let rows = (0..leaf)
+                        .map(|dy| {
+                            (0..leaf)
+                                .filter(|dx| map.check_point(x * leaf + dx, y * leaf + dy))
+                                .fold(0, |row, dx| row | 1 << dx)
+                        })
+                        .collect();
+                    TerrainEdit { layer, x, y, rows }
+                })
+                .collect(),
+        })
+    }
+
+    // Put a freshly loaded level back the way it was saved. Edits only make sense
+    // on top of the whole map so any terrain still waiting to be rasterized goes in first.
+    fn restore(&mut self, save: &SaveGame, terrain_chunks: &mut Vec<TerrainChunk>) {
+        for chunk in terrain_chunks.drain(..) {
+            match chunk {
+                TerrainChunk::Foreground(s) => self.add_foreground(&s),
+                TerrainChunk::Background(s) => self.add_background(&s),
+                TerrainChunk::Terrain(s) => self.add_terrain(&s),
+            }
+        }
+        let leaf = COLLISION_MAP_LEAF_SIZE as i32;
+        for edit in &save.terrain {
+            let map = if edit.layer == 1 {
+                &mut self.collision_map
+            } else {
+                &mut self.foreground_map
+            };
+            let (x, y) = (edit.x * leaf, edit.y * leaf);
+            map.remove_rect(x, y, leaf as u32, leaf as u32);
+            for (dy, row) in edit.rows.iter().enumerate() {
+                for dx in 0..leaf {
+                    if row & 1 << dx != 0 {
+                        let _ = map.insert(x + dx, y + dy as i32);
+                    }
+                }
+            }
+            for (dy, row) in edit.rows.iter().enumerate() {
+                for dx in 0..leaf {
+                    if row & 1 << dx == 0 {
+                        self.clear_colors(edit.layer, x + dx, y + dy as i32, 1, 1);
+                    }
+                }
+            }
+            self.invalidate_rect(edit.layer, x, y, leaf as u32, leaf as u32);
+            self.terrain_edits.insert((edit.layer, edit.x, edit.y));
+        }
+
+        for potion_id in &save.consumed {
+            self.potions.retain(|(id, _)| id != potion_id);
+            self.sprites.remove(potion_id);
+            self.sprite_cache.remove(potion_id);
+        }
+        self.consumed = save.consumed.clone();
+        for card_id in &save.picked {
+            self.collectables.retain(|id| id != card_id);
+            if let Some(card) = self.sprites.remove(card_id) {
+                self.sprite_cache.remove(card_id);
+                self.collected.insert(*card_id, card);
+            }
+        }
+        for card in &save.cards {
+            let (r, g, b, a) = card.color;
+            let sprite = Sprite::new(
+                &card.collider,
+                card.loc.0,
+                card.loc.1,
+                card.scale.0,
+                card.scale.1,
+                Color { r, g, b, a },
+            );
+            let id = self.next_id;
+            self.next_id += 1;
+            self.collected.insert(id, sprite);
+        }
+        self.score = save.score;
+        self.largest_collected = save.largest_collected;
+
+        self.spawn = Vector::new(save.loc.0, save.loc.1);
+        self.spawn_scale = save.scale;
+        if let Some(player) = self.player_id().and_then(|id| self.sprites.get_mut(&id)) {
+            player.loc = Vector::new(save.loc.0, save.loc.1);
+            player.x_scale = save.scale.0;
+            player.y_scale = save.scale.1;
+            for potion in &save.pending_potions {
+                player.queue_potion(*potion);
+            }
+        }
+    }
+
+    fn add_trigger(&mut self, area: vek::geom::Rect<f32, f32>) -> usize {
+        self.triggers.push(Trigger {
+            area,
+            occupied: false,
+        });
+        self.triggers.len() - 1
+    }
+
+    // Bring the score and the cards picked up so far into a freshly loaded level
+    fn carry_over(&mut self, previous: Scene) {
+        self.score += previous.score;
+        self.largest_collected = self.largest_collected.max(previous.largest_collected);
+        for (_, sprite) in previous.collected {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.collected.insert(id, sprite);
+        }
+    }
+
+    fn listen(&mut self, event: SceneEvent, actions: Vec<Action>, once: bool) {
+        if !actions.is_empty() {
+            self.listeners.push(Listener {
+                event,
+                actions,
+                once,
+            });
+        }
+    }
+
+    // Compile a script and have each of its functions named after an event listen for it
+    fn attach_script(
+        &mut self,
+        source: &str,
+        this: Option<usize>,
+        events: &[(&str, SceneEvent)],
+        once: bool,
+    ) {
+        let ast = match self.scripting.engine.compile(source) {
+            Ok(ast) => ast,
+            Err(e) => {
+                self.message = Some((format!("Bad level script: {}", e), 5.0));
+                return;
+            }
+        };
+        let script = self.scripting.scripts.len();
+        let defined: Vec<String> = ast.iter_functions().map(|f| f.name.to_string()).collect();
+        self.scripting.scripts.push(ast);
+        for (function, event) in events {
+            if defined.iter().any(|name| name == function) {
+                self.listen(
+                    *event,
+                    vec![Action::Script {
+                        script,
+                        function: function.to_string(),
+                        this,
+                    }],
+                    once,
+                );
+            }
+        }
+    }
+
+    fn run_script(&mut self, script: usize, function: &str, this: Option<usize>) {
+        let world = ScriptWorld {
+            sprites: std::mem::take(&mut self.sprites),
+            potions: std::mem::take(&mut self.potions),
+            collision_map: std::mem::replace(
+                &mut self.collision_map,
+                CollisionTree::new(0, 0, 0, 0),
+            ),
+            score: self.score,
+            scale_limits: self.scale_limits,
+            player: self.player_id(),
+            this,
+            queued: vec![],
+        };
+        *self.scripting.world.borrow_mut() = Some(world);
+        let result = self.scripting.engine.call_fn_with_options::<rhai::Dynamic>(
+            rhai::CallFnOptions::new().eval_ast(false),
+            &mut rhai::Scope::new(),
+            &self.scripting.scripts[script],
+            function,
+            (),
+        );
+        let world = self.scripting.world.borrow_mut().take().unwrap();
+        self.sprites = world.sprites;
+        self.potions = world.potions;
+        self.collision_map = world.collision_map;
+        // Scripts are for designers, so put their mistakes where they'll be seen
+        if let Err(e) = result {
+            self.message = Some((e.to_string(), 5.0));
+        }
+        self.run_actions(&world.queued);
+    }
+
+    fn park(&mut self, sprite_id: usize, name: String) {
+        let sprite = match self.sprites.remove(&sprite_id) {
+            Some(sprite) => sprite,
+            None => return,
+        };
+        self.sprite_cache.remove(&sprite_id);
+        let role = if let Some(i) = self.potions.iter().position(|(id, _)| *id == sprite_id) {
+            Role::Potion(self.potions.remove(i).1)
+        } else if let Some(i) = self.platforms.iter().position(|(id, _)| *id == sprite_id) {
+            Role::Platform(self.platforms.remove(i).1)
+        } else if let Some(i) = self.bombs.iter().position(|(id, _)| *id == sprite_id) {
+            Role::Bomb(self.bombs.remove(i).1)
+        } else if let Some(i) = self.collectables.iter().position(|id| *id == sprite_id) {
+            self.collectables.remove(i);
+            Role::Collectable
+        } else if let Some(i) = self.characters.iter().position(|id| *id == sprite_id) {
+            self.characters.remove(i);
+            let npc = self
+                .npcs
+                .iter()
+                .position(|(id, _)| *id == sprite_id)
+                .map(|i| self.npcs.remove(i).1);
+            Role::Character(npc)
+        } else {
+            Role::Plain
+        };
+        self.parked
+            .entry(name)
+            .or_default()
+            .push((sprite_id, sprite, role));
+    }
+
+    fn spawn(&mut self, name: &str) {
+        for (id, sprite, role) in self.parked.remove(name).unwrap_or_default() {
+            self.sprites.insert(id, sprite);
+            match role {
+                Role::Plain => (),
+                Role::Character(npc) => {
+                    self.characters.push(id);
+                    if let Some(npc) = npc {
+                        self.npcs.push((id, npc));
+                    }
+                }
+                Role::Collectable => self.collectables.push(id),
+                Role::Potion(potion) => self.potions.push((id, potion)),
+                Role::Platform(platform) => self.platforms.push((id, platform)),
+                Role::Bomb(bomb) => self.bombs.push((id, bomb)),
+            }
+        }
+    }
+
+    fn bake_colors(&mut self, layer: u32, sprite: &Sprite) {
+        if sprite.collider.colors.is_none() {
+            return;
+        }
+        let tile_size = TILE_SIZE as i32;
+        for dx in 0..sprite.collider.width {
+            for dy in 0..sprite.collider.height {
+                let color = match sprite.collider.color(dx, dy) {
+                    Some(color) if sprite.collider.get(dx, dy) => color,
+                    _ => continue,
+                };
+                let (rx, ry, w, h) = sprite.pixel_rect(dx as i32, dy as i32);
+                for x in rx..rx + w as i32 {
+                    for y in ry..ry + h as i32 {
+                        let tile = self
+                            .tile_colors
+                            .entry((layer, x.div_euclid(tile_size), y.div_euclid(tile_size)))
+                            .or_insert_with(|| vec![0; (TILE_SIZE * TILE_SIZE * 4) as usize]);
+                        let i = (x.rem_euclid(tile_size) + y.rem_euclid(tile_size) * tile_size)
+                            as usize
+                            * 4;
+                        tile[i..i + 4].copy_from_slice(&color);
+                    }
+                }
+            }
+        }
+    }
+
+    // Forget the baked colors under a rect once its pixels are gone, so anything
+    // that lands there later gets its own color
+    fn clear_colors(&mut self, layer: u32, x: i32, y: i32, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let tile_size = TILE_SIZE as i32;
+        let (right, bottom) = (x + width as i32, y + height as i32);
+        for tx in x.div_euclid(tile_size)..=(right - 1).div_euclid(tile_size) {
+            for ty in y.div_euclid(tile_size)..=(bottom - 1).div_euclid(tile_size) {
+                let (x0, y0) = (tx * tile_size, ty * tile_size);
+                let (left, top) = (x.max(x0), y.max(y0));
+                let (right, bottom) = (right.min(x0 + tile_size), bottom.min(y0 + tile_size));
+                if (left, top, right, bottom) == (x0, y0, x0 + tile_size, y0 + tile_size) {
+                    self.tile_colors.remove(&(layer, tx, ty));
+                    continue;
+                }
+                if let Some(tile) = self.tile_colors.get_mut(&(layer, tx, ty)) {
+                    for yy in top..bottom {
+                        for xx in left..right {
+                            tile[((xx - x0) + (yy - y0) * tile_size) as usize * 4 + 3] = 0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_terrain(&mut self, sprite: &Sprite) {
+        self.collision_map.add_sprite(sprite);
+        self.bake_colors(1, sprite);
+        for x in sprite.loc.x as i32..sprite.loc.x as i32 + sprite.width() as i32 {
+            for y in sprite.loc.y as i32..sprite.loc.y as i32 + sprite.height() as i32 {
+                self.tile_queue
+                    .insert((1, x / TILE_SIZE as i32, y / TILE_SIZE as i32));
+                self.tile_cache
+                    .entry((x / TILE_SIZE as i32, y / TILE_SIZE as i32))
+                    .or_default()
+                    .1 = (None, None);
+            }
+        }
+    }
+
+    fn clear_terrain(&mut self, sprite: Sprite) {
+        for x in sprite.loc.x as i32..sprite.loc.x as i32 + sprite.width() as i32 {
+            for y in sprite.loc.y as i32..sprite.loc.y as i32 + sprite.height() as i32 {
+                self.tile_cache
+                    .remove(&(x / TILE_SIZE as i32, y / TILE_SIZE as i32));
+            }
+        }
+        for dx in 0..sprite.collider.width {
+            for dy in 0..sprite.collider.height {
+                if sprite.collider.get(dx, dy) {
+                    let (x, y, w, h) = sprite.pixel_rect(dx as i32, dy as i32);
+                    self.clear_colors(1, x, y, w, h);
+                }
+            }
+        }
+        self.collision_map.clear_sprite(sprite);
+    }
+
+    fn add_foreground(&mut self, sprite: &Sprite) {
+        self.foreground_map.add_sprite(sprite);
+        self.bake_colors(2, sprite);
+        for x in sprite.loc.x as i32..sprite.loc.x as i32 + sprite.width() as i32 {
+            for y in sprite.loc.y as i32..sprite.loc.y as i32 + sprite.height() as i32 {
+                self.tile_cache
+                    .entry((x / TILE_SIZE as i32, y / TILE_SIZE as i32))
+                    .or_default()
+                    .2 = (None, None);
+                self.tile_queue
+                    .insert((2, x / TILE_SIZE as i32, y / TILE_SIZE as i32));
+            }
+        }
+    }
+
+    fn add_background(&mut self, sprite: &Sprite) {
+        self.background_map.add_sprite(sprite);
+        self.bake_colors(0, sprite);
+        for x in sprite.loc.x as i32..sprite.loc.x as i32 + sprite.width() as i32 {
+            for y in sprite.loc.y as i32..sprite.loc.y as i32 + sprite.height() as i32 {
+                self.tile_cache
+                    .entry((x / TILE_SIZE as i32, y / TILE_SIZE as i32))
+                    .or_default()
+                    .0 = (None, None);
+                self.tile_queue
+                    .insert((0, x / TILE_SIZE as i32, y / TILE_SIZE as i32));
+            }
+        }
+    }
+
+    fn step_platforms(&mut self, fps: f32) {
+        let platform_ids: IndexSet<usize> = self.platforms.iter().map(|(id, _)| *id).collect();
+        let mut moves = vec![];
+        for (i, (platform_id, platform)) in self.platforms.iter().enumerate() {
+            // Worked out on a copy, a platform that can't move this step stays where it was
+            let mut platform = platform.clone();
+            let sprite = &self.sprites[platform_id];
+            let height = sprite.height() as f32;
+            let mut remaining = platform.speed / fps;
+            let mut loc = sprite.loc;
+            while remaining > 0.0 && platform.path.len() > 1 {
+                let target = platform.path[platform.target] - Vector::new(0.0, height);
+                let d = loc.distance(target);
+                if d <= remaining {
+                    loc = target;
+                    remaining -= d;
+                    platform.advance();
+                } else {
+                    loc += (target - loc) * (remaining / d);
+                    remaining = 0.0;
+                }
+            }
+            if loc != sprite.loc {
+                moves.push((i, *platform_id, loc, platform));
+            }
+        }
+
+        for (i, platform_id, loc, platform) in moves {
+            let old = self.sprites[&platform_id].clone();
+            let delta = loc - old.loc;
+            let mut moved = old.clone();
+            moved.loc = loc;
+
+            let mut carried = vec![];
+            for (other_id, other) in &self.sprites {
+                if platform_ids.contains(other_id) {
+                    continue;
+                }
+                let riding = !other.overlap(&old)
+                    && other.overlap_at(other.loc + Vector::new(0.0, 1.0), &old);
+                if riding || other.overlap(&moved) {
+                    let mut target = other.loc + delta;
+                    let step = delta.normalize();
+                    let mut tries = other.width().max(other.height()) as i32;
+                    while other.overlap_at(target, &moved) && tries > 0 {
+                        target += step;
+                        tries -= 1;
+                    }
+                    carried.push((*other_id, target - other.loc));
+                }
+            }
+            // Pinning a rider against terrain stops the platform rather than pushing
+            // the rider into the terrain or the platform into the rider
+            let pinned = carried.iter().any(|(other_id, delta)| {
+                let mut test = self.sprites[other_id].clone();
+                test.loc += *delta;
+                self.sprite_blocked(&test)
+            });
+            if pinned {
+                continue;
+            }
+            for (other_id, delta) in carried {
+                self.sprites.get_mut(&other_id).unwrap().loc += delta;
+            }
+            self.sprites.get_mut(&platform_id).unwrap().loc = loc;
+            self.platforms[i].1 = platform;
+        }
+    }
+
+    // Steer NPCs the same way input steers the player, by setting their velocity
+    fn step_npcs(&mut self, fps: f32) {
+        let (player_center, player_scale) = match self.player_id() {
+            Some(id) => {
+                let player = &self.sprites[&id];
+                let area = player.area();
+                (
+                    Vector::new(area.x + area.w / 2.0, area.y + area.h / 2.0),
+                    player.x_scale.max(player.y_scale),
+                )
+            }
+            None => return,
+        };
+        for (npc_id, npc) in &mut self.npcs {
+            let sprite = match self.sprites.get_mut(npc_id) {
+                Some(sprite) => sprite,
+                None => continue,
+            };
+            let area = sprite.area();
+            let center = Vector::new(area.x + area.w / 2.0, area.y + area.h / 2.0);
+            let stuck = sprite.velocity.x != 0.0 && sprite.blocked_x;
+            let near = center.distance(player_center) < npc.range;
+            let toward_player = if (player_center.x - center.x).abs() < area.w / 2.0 {
+                0.0
+            } else {
+                (player_center.x - center.x).signum()
+            };
+            if npc.behavior == Behavior::Sleep && near {
+                npc.behavior = Behavior::Chase;
+            }
+            let direction = match npc.behavior {
+                Behavior::Patrol => {
+                    let offset = sprite.loc.x - npc.origin;
+                    if stuck || (offset.abs() > npc.range && offset.signum() == npc.direction) {
+                        npc.direction = -npc.direction;
+                    }
+                    npc.direction
+                }
+                Behavior::Chase if near => toward_player,
+                Behavior::Flee if near && player_scale > sprite.x_scale.max(sprite.y_scale) => {
+                    -toward_player
+                }
+                _ => 0.0,
+            };
+            let speed = npc.speed * self.movement.walk_speed.multiplier(sprite.size());
+            sprite.velocity.x = direction * sprite.walk_velocity(speed, fps);
+            // Try hopping over whatever it walked into, patrols just turn around
+            if stuck
+                && direction != 0.0
+                && sprite.ground_contact
+                && npc.behavior != Behavior::Patrol
+            {
+                sprite.jumping = true;
+                sprite.velocity.y = sprite.jump_velocity(&self.movement, fps);
+            }
+        }
+    }
+
+    // Move a sprite by delta unless that would put it in terrain
+    fn shove(&mut self, sprite_id: usize, delta: Vector) -> bool {
+        let mut test = self.sprites[&sprite_id].clone();
+        test.loc += delta;
+        if self.sprite_blocked(&test) {
+            return false;
+        }
+        self.sprites.get_mut(&sprite_id).unwrap().loc = test.loc;
+        true
+    }
+
+    // Characters only interact through their relative size, see SizeRules
+    fn step_size_rules(&mut self, before: &HashMap<usize, Vector>) {
+        let rules = self.size_rules;
+        let characters = self.characters.clone();
+        let mut squashed: Vec<usize> = vec![];
+        for (i, a_id) in characters.iter().enumerate() {
+            for b_id in &characters[i + 1..] {
+                if squashed.contains(a_id) || squashed.contains(b_id) {
+                    continue;
+                }
+                let size = |id: &usize| {
+                    let sprite = &self.sprites[id];
+                    sprite.x_scale.max(sprite.y_scale)
+                };
+                let (big_id, small_id) = if size(a_id) >= size(b_id) {
+                    (*a_id, *b_id)
+                } else {
+                    (*b_id, *a_id)
+                };
+                let ratio = size(&big_id) / size(&small_id);
+                let big = &self.sprites[&big_id];
+                let small = &self.sprites[&small_id];
+                let big_area = big.area();
+                let small_area = small.area();
+                let side_by_side = small_area.x < big_area.x + big_area.w
+                    && small_area.x + small_area.w > big_area.x;
+                let small_bottom = small_area.y + small_area.h;
+                let big_bottom = big_area.y + big_area.h;
+                // A fast fall can carry the big one past the small one's top in a single
+                // step, so compare where both were before physics ran
+                let big_before = before.get(&big_id).copied().unwrap_or(big.loc);
+                let small_before = before.get(&small_id).copied().unwrap_or(small.loc);
+                let landing = big.loc.y > big_before.y
+                    && big_before.y + big_area.h <= small_before.y + small_area.h / 2.0
+                    && big_bottom >= small_area.y;
+                let resting = small.velocity.y >= 0.0
+                    && small_bottom >= big_area.y - 1.0
+                    && small_bottom <= big_area.y + big_area.h / 4.0;
+
+                if ratio >= rules.stomp && side_by_side && landing {
+                    squashed.push(small_id);
+                } else if ratio >= rules.ride && side_by_side && resting {
+                    let carried = big.loc - before.get(&big_id).copied().unwrap_or(big.loc);
+                    let delta = Vector::new(carried.x, big_area.y - small_bottom);
+                    if self.shove(small_id, delta) {
+                        let rider = self.sprites.get_mut(&small_id).unwrap();
+                        rider.velocity.y = 0.0;
+                        rider.ground_contact = true;
+                        rider.jumping = false;
+                    }
+                } else if ratio >= rules.slip || !big.overlap(small) {
+                    continue;
+                } else {
+                    let away = if small_area.x + small_area.w / 2.0 >= big_area.x + big_area.w / 2.0
+                    {
+                        1.0
+                    } else {
+                        -1.0
+                    };
+                    let depth = if away > 0.0 {
+                        big_area.x + big_area.w - small_area.x
+                    } else {
+                        small_area.x + small_area.w - big_area.x
+                    };
+                    if ratio >= rules.push {
+                        self.shove(small_id, Vector::new(away * depth, 0.0));
+                    } else {
+                        let half = away * (depth / 2.0).max(1.0);
+                        self.shove(small_id, Vector::new(half, 0.0));
+                        self.shove(big_id, Vector::new(-half, 0.0));
+                    }
+                }
+            }
+        }
+        for sprite_id in squashed {
+            if self.sprites[&sprite_id].is_player {
+                self.run_actions(&[Action::Lose]);
+            } else {
+                self.sprites.remove(&sprite_id);
+                self.sprite_cache.remove(&sprite_id);
+                self.characters.retain(|id| *id != sprite_id);
+                self.npcs.retain(|(id, _)| *id != sprite_id);
+                self.events.push(SceneEvent::SpriteDestroyed(sprite_id));
+            }
+        }
+    }
+
+    fn step_physics(&mut self, camera: Vector, camera_scale: f32, fps: f32) {
+        self.step_platforms(fps);
+        self.step_npcs(fps);
+        let before: HashMap<usize, Vector> = self
+            .characters
+            .iter()
+            .map(|id| (*id, self.sprites[id].loc))
+            .collect();
+        let platforms: Vec<Sprite> = self
+            .platforms
+            .iter()
+            .map(|(id, _)| self.sprites[id].clone())
+            .collect();
+
+        for (sprite_id, sprite) in self.sprites.iter_mut() {
+            if camera.distance(sprite.loc) > 1920.0 * camera_scale {
+                continue;
+            }
+            if self.platforms.iter().any(|(id, _)| id == sprite_id) {
+                continue;
+            }
+
+            if sprite.gravity {
+                sprite.velocity.y += self.movement.gravity.at(sprite.size()) / fps
+                    * sprite.gravity_scale
+                    * sprite.fall_scale();
+            }
+            let mut blocked_y = false;
+            let mut blocked_x = false;
+            let falling = sprite.velocity.y > 0.0;
+            // Whole pixels are stepped, the remainder carries to the next frame
+            let move_x = sprite.velocity.x * sprite.x_scale + sprite.vx_slop;
+            let move_y = sprite.velocity.y * sprite.y_scale + sprite.vy_slop;
+            sprite.vx_slop = move_x.fract();
+            sprite.vy_slop = move_y.fract();
+            for (mut vx, mut vy) in vec![(0, move_y.trunc() as i32), (move_x.trunc() as i32, 0)] {
+                {
+                    let mut loc_x = sprite.loc.x;
+                    let mut loc_y = sprite.loc.y;
+
+                    let step_x = (sprite.x_scale / 8.0)
+                        .min(1.0)
+                        .min(sprite.velocity.x.abs())
+                        .max(1.0)
+                        .copysign(move_x);
+                    let step_y = (sprite.y_scale / 8.0)
+                        .min(1.0)
+                        .min(sprite.velocity.y.abs())
+                        .max(1.0)
+                        .copysign(move_y);
+
+                    'outer: while vy.abs() >= 1 || vx.abs() >= 1 {
+                        if vy.abs() >= 1 {
+                            loc_y += step_y;
+                        } else {
+                            loc_x += step_x;
+                        }
+                        for dx in 0..sprite.collider.width {
+                            for dy in 0..sprite.collider.height {
+                                if sprite.collider.get(dx, dy) {
+                                    let (x, y, w, h) = sprite.pixel_rect_at(
+                                        Vector::new(loc_x, loc_y),
+                                        dx as i32,
+                                        dy as i32,
+                                    );
+                                    let in_rubble = self.rubble_map.check_rect(x, y, w, h);
+                                    if (!in_rubble && self.collision_map.check_rect(x, y, w, h))
+                                        || platforms.iter().any(|p| p.check_rect(x, y, w, h))
+                                    {
+                                        if vy.abs() >= 1 {
+                                            blocked_y = true;
+                                        } else {
+                                            blocked_x = true;
+                                        }
+                                        break 'outer;
+                                    }
+                                }
+                            }
+                        }
+                        if vy.abs() >= 1 {
+                            vy -= step_y as i32;
+                        } else {
+                            vx -= step_x as i32;
+                        }
+                        sprite.loc.x = loc_x;
+                        sprite.loc.y = loc_y;
+                    }
+                }
+            }
+            if blocked_x {
+                sprite.vx_slop = 0.0;
+            }
+            sprite.blocked_x = blocked_x;
+            if !blocked_y {
+                if sprite.velocity.y.abs() >= 1.0 {
+                    sprite.ground_contact = false;
+                }
+            } else {
+                if falling {
+                    sprite.ground_contact = true;
+                    sprite.jumping = false;
+                }
+                sprite.velocity.y = 0.0;
+                sprite.vy_slop = 0.0;
+            }
+            if sprite.ground_contact {
+                let friction = self.movement.friction.at(sprite.size()) / fps;
+                if sprite.velocity.x >= 0.0 {
+                    sprite.velocity.x = (sprite.velocity.x - friction).max(0.0);
+                } else {
+                    sprite.velocity.x = (sprite.velocity.x + friction).min(0.0);
+                }
+            }
+            if sprite.velocity.x.abs() > 1.0 || sprite.velocity.y.abs() > 1.0 {
+                sprite.sleep_timer = 0.0;
+            } else {
+                sprite.sleep_timer += 1.0 / fps;
+            }
+            if sprite.is_player {
+                //&& sprite.y_scale < 80 {
+                // Collision resolution
+                let mut x_dir = 0;
+                let mut y_dir = 0;
+                for dx in 0..sprite.collider.width {
+                    for dy in 0..sprite.collider.height {
+                        if sprite.collider.get(dx, dy) {
+                            let (x, y, w, h) = sprite.pixel_rect(dx as i32, dy as i32);
+                            if self.rubble_map.check_rect(x, y, w, h) {
+                            } else if self.collision_map.check_rect(x, y, w, h) {
+                                if dx <= sprite.collider.width / 2 {
+                                    x_dir += 1;
+                                } else {
+                                    x_dir -= 1;
+                                }
+                                if dy <= sprite.collider.height / 2 {
+                                    y_dir += 1;
+                                } else {
+                                    y_dir -= 1;
+                                }
+                            }
+                        }
+                    }
+                }
+                sprite.loc.x += x_dir.clamp(-1, 1) as f32 * sprite.x_scale.max(1.0);
+                sprite.loc.y += y_dir.clamp(-1, 1) as f32 * sprite.y_scale.max(1.0);
+            }
+        }
+
+        self.step_size_rules(&before);
+        self.step_particles(camera, camera_scale, fps);
+        self.step_sand();
+        self.step_rubble(camera, camera_scale, fps);
+
+        self.step_triggers(fps);
+        self.step_hazards(fps);
+
+        let mut drinkers = vec![];
+        let mut consumed: IndexSet<usize> = IndexSet::default();
+        let mut collected: IndexSet<usize> = IndexSet::default();
+        for character_id in &self.characters {
+            let character = &self.sprites[character_id];
+            for (potion_id, drink) in &self.potions {
+                if consumed.contains(potion_id) {
+                    continue;
+                }
+                let potion = &self.sprites[potion_id];
+                if character.overlap(potion) {
+                    consumed.insert(*potion_id);
+                    drinkers.push((*character_id, *drink));
+                }
+            }
+            for collectable_id in &self.collectables {
+                if collected.contains(collectable_id) {
+                    continue;
+                }
+                let collectable = &self.sprites[collectable_id];
+                if character.overlap(collectable) {
+                    self.largest_collected = self
+                        .largest_collected
+                        .max(collectable.x_scale.max(collectable.y_scale));
+                    collected.insert(*collectable_id);
+                }
+            }
+        }
+        for potion_id in consumed {
+            self.potions.retain(|(id, _)| *id != potion_id);
+            self.sprites.remove(&potion_id);
+            self.sprite_cache.remove(&potion_id);
+            self.events.push(SceneEvent::PotionDrunk(potion_id));
+            self.consumed.push(potion_id);
+        }
+        for collectable_id in collected {
+            self.collectables.retain(|id| *id != collectable_id);
+            self.collected.insert(
+                collectable_id,
+                self.sprites.remove(&collectable_id).unwrap(),
+            );
+            self.score += 1;
+            self.events
+                .push(SceneEvent::CollectablePicked(collectable_id));
+        }
+        self.dispatch_events();
+        for (sprite_id, potion) in drinkers {
+            let sprite = self.sprites.get_mut(&sprite_id).unwrap();
+            sprite.queue_potion(potion);
+        }
+        self.check_conditions();
+        self.step_bombs(fps);
+        self.dispatch_events();
+
+        for character_id in self.characters.clone() {
+            self.step_scale_tween(character_id, fps);
+            let sprite = self.sprites.get_mut(&character_id).unwrap();
+            sprite.step_active_potions(fps);
+            if let Some(t) = sprite.blocked_timer.as_mut() {
+                *t -= 1.0 / fps;
+                if *t <= 0.0 {
+                    sprite.blocked_timer.take();
+                }
+            }
+            if let Some(time) = sprite.potion_timer.as_mut() {
+                *time -= 1.0 / fps;
+                if *time > 0.0 && !self.final_potion_triggered {
+                    continue;
+                }
+                if *time < -1.0 {
+                    sprite.potion_timer.take();
+                }
+                let mut x_scale = sprite.x_scale;
+                let mut y_scale = sprite.y_scale;
+                let pending: Vec<_> = sprite.pending_potions.drain(..).collect();
+                let mut anchor = ScaleAnchor::Feet;
+                let mut growth = GrowthPolicy::Smash;
+                let mut destruction = DestructionProfile::default();
+                let limits = self.scale_limits;
+                let mut timed_scale = vec![];
+                for potion in pending {
+                    anchor = potion.anchor;
+                    growth = potion.growth;
+                    destruction = potion.destruction;
+                    let (before_x, before_y) = (x_scale, y_scale);
+                    // Scale effects work out their revert after clamping and the growth policy
+                    // so it only undoes the change that actually happened
+                    let revert = match potion.effect {
+                        PotionType::Relative(dx, dy) => {
+                            x_scale += dx;
+                            y_scale += dy;
+                            None
+                        }
+                        PotionType::Absolute(x, y) => {
+                            if let Some(x) = x {
+                                x_scale = x;
+                            }
+                            if let Some(y) = y {
+                                y_scale = y;
+                            }
+                            None
+                        }
+                        PotionType::Multiplicative(x, y) => {
+                            x_scale *= x;
+                            y_scale *= y;
+                            None
+                        }
+                        PotionType::Gravity(g) => {
+                            sprite.gravity_scale *= g;
+                            Some(PotionType::Gravity(1.0 / g))
+                        }
+                        PotionType::Jump(j) => {
+                            sprite.jump_scale *= j;
+                            Some(PotionType::Jump(1.0 / j))
+                        }
+                        PotionType::Stretch(k) => {
+                            x_scale /= k;
+                            y_scale *= k;
+                            None
+                        }
+                    };
+                    let timed = potion.duration.is_some() || potion.period.is_some();
+                    match revert {
+                        Some(revert) if timed => sprite.active_potions.push(ActivePotion {
+                            potion,
+                            revert,
+                            elapsed: 0.0,
+                            phase: 0.0,
+                            applied: true,
+                        }),
+                        Some(_) => (),
+                        None => {
+                            let (x, y) = limits.clamp((x_scale, y_scale));
+                            x_scale = x;
+                            y_scale = y;
+                            if timed {
+                                timed_scale.push((potion, (x - before_x, y - before_y)));
+                            }
+                        }
+                    }
+                }
+                if self.end_sequence_triggered {
+                    self.final_potion_triggered = true;
+                }
+
+                let target = if self.final_potion_triggered {
+                    self.scale_limits.clamp((
+                        sprite.x_scale + self.overgrow_step,
+                        sprite.y_scale + self.overgrow_step,
+                    ))
+                } else {
+                    self.scale_limits.clamp((x_scale, y_scale))
+                };
+                if !self.final_potion_triggered && target == (sprite.x_scale, sprite.y_scale) {
+                    sprite.activate_scale_potions(timed_scale, target, target, target);
+                    continue;
+                }
+                let duration = if self.final_potion_triggered {
+                    0.0
+                } else {
+                    SCALE_TWEEN_DURATION
+                };
+                let mut tween = ScaleTween {
+                    from: (sprite.x_scale, sprite.y_scale),
+                    to: target,
+                    planned: target,
+                    elapsed: 0.0,
+                    duration,
+                    anchor,
+                    growth,
+                    destruction,
+                    timed: timed_scale,
+                };
+                let growing = target.0 > sprite.x_scale || target.1 > sprite.y_scale;
+                if growing && !self.final_potion_triggered {
+                    match growth {
+                        GrowthPolicy::Smash => (),
+                        GrowthPolicy::Block => {
+                            if !self.fits_at_scale(character_id, target, anchor) {
+                                let sprite = self.sprites.get_mut(&character_id).unwrap();
+                                // The growth never happened so the scale potions have nothing
+                                // to revert, gravity and jump potions still took effect
+                                sprite.blocked_timer = Some(BLOCKED_FEEDBACK_TIME);
+                                continue;
+                            }
+                        }
+                        GrowthPolicy::Squeeze => {
+                            tween.to = self.largest_fit(character_id, tween.from, target, anchor);
+                        }
+                    }
+                }
+                let sprite = self.sprites.get_mut(&character_id).unwrap();
+                // A tween cut off by this one settles its potions where it got to
+                if let Some(old) = sprite.scale_tween.take() {
+                    let reached = (sprite.x_scale, sprite.y_scale);
+                    sprite.activate_scale_potions(old.timed, old.from, old.planned, reached);
+                }
+                sprite.scale_tween = Some(tween);
+            }
+        }
+        self.step_facing();
+        self.step_animations(fps);
+    }
+
+    fn player_id(&self) -> Option<usize> {
+        self.characters
+            .iter()
+            .copied()
+            .find(|id| self.sprites[id].is_player)
     }
 
-    fn add_character(&mut self, sprite: Sprite) -> usize {
-        let id = self.add_sprite(sprite);
-        self.characters.push(id);
-        id
+    fn condition_met(&self, condition: Condition) -> bool {
+        let player = match self.player_id() {
+            Some(id) => &self.sprites[&id],
+            None => return false,
+        };
+        match condition {
+            Condition::Score(score) => self.score >= score,
+            Condition::CollectableScale(scale) => self.largest_collected >= scale,
+            Condition::MaxScale => self.scale_limits.at_max(player),
+            Condition::Below(y) => player.loc.y > y,
+        }
     }
 
-    fn add_terrain(&mut self, sprite: &Sprite) {
-        self.collision_map.add_sprite(sprite);
-        for x in
-            sprite.loc.x as i32..sprite.loc.x as i32 + SPRITE_WIDTH as i32 * sprite.x_scale as i32
-        {
-            for y in sprite.loc.y as i32
-                ..sprite.loc.y as i32 + SPRITE_WIDTH as i32 * sprite.y_scale as i32
-            {
-                self.tile_queue
-                    .insert((1, x / TILE_SIZE as i32, y / TILE_SIZE as i32));
-                self.tile_cache
-                    .entry((x / TILE_SIZE as i32, y / TILE_SIZE as i32))
-                    .or_default()
-                    .1 = (None, None);
-            }
+    fn check_conditions(&mut self) {
+        if self.done {
+            return;
+        }
+        if self.win_conditions.iter().any(|c| self.condition_met(*c)) {
+            self.run_actions(&[Action::Win]);
+        } else if self.lose_conditions.iter().any(|c| self.condition_met(*c)) {
+            self.run_actions(&[Action::Lose]);
         }
     }
 
-    fn clear_terrain(&mut self, sprite: Sprite) {
-        for x in
-            sprite.loc.x as i32..sprite.loc.x as i32 + SPRITE_WIDTH as i32 * sprite.x_scale as i32
-        {
-            for y in sprite.loc.y as i32
-                ..sprite.loc.y as i32 + SPRITE_WIDTH as i32 * sprite.y_scale as i32
-            {
-                self.tile_cache
-                    .remove(&(x / TILE_SIZE as i32, y / TILE_SIZE as i32));
+    fn run_actions(&mut self, actions: &[Action]) {
+        for action in actions {
+            self.run_action(action);
+        }
+    }
+
+    fn run_action(&mut self, action: &Action) {
+        let player_id = self.player_id();
+        match action {
+            Action::SetPotions(effect) => {
+                self.potions
+                    .iter_mut()
+                    .for_each(|(_, p)| *p = Potion::new(*effect));
+            }
+            Action::Overgrow(step) => {
+                self.end_sequence_triggered = true;
+                self.overgrow_step = *step;
+            }
+            Action::Teleport(loc) => {
+                if let Some(player) = player_id.and_then(|id| self.sprites.get_mut(&id)) {
+                    player.loc = *loc;
+                    player.velocity = Vector::new(0.0, 0.0);
+                }
+            }
+            Action::GatherCollected {
+                center,
+                spread,
+                scale,
+            } => {
+                for (i, mut collectable) in self.collected.drain(..) {
+                    collectable.gravity = false;
+                    collectable.velocity = Vector::new(0.0, 0.0);
+                    if collectable.x_scale < *scale {
+                        collectable.x_scale = *scale;
+                        collectable.y_scale = *scale;
+                        let x = (i as f32 * 1000.0).sin() * spread + center.x;
+                        let y = (i as f32 * 3000.0).sin() * spread + center.y;
+                        collectable.loc = Vector::new(x, y);
+                    }
+                    self.sprites.insert(i, collectable);
+                }
+            }
+            Action::ClearLevel => {
+                self.sprites.retain(|_, s| s.is_player);
+                self.characters.retain(|id| Some(*id) == player_id);
+                self.npcs.clear();
+                self.particles.clear();
+                self.collectables.clear();
+                self.potions.clear();
+                self.platforms.clear();
+                self.bombs.clear();
+                self.collision_map.clear();
+                self.rubble_map.clear();
+                self.rubble_timers.clear();
+                self.foreground_map.clear();
+                self.background_map.clear();
+                self.tile_cache.clear();
+                self.tile_queue.clear();
+                self.tile_colors.clear();
+                self.sand_dirty.clear();
+                self.materials.clear();
+                // Nothing from the old level should keep reacting once it's gone
+                self.hazards.clear();
+                self.triggers.clear();
+                self.events.clear();
+                self.listeners.clear();
+                self.parked.clear();
+                self.underwater = 0.0;
+            }
+            Action::Camera { target, zoom } => {
+                self.camera_override = Some((*target, *zoom));
+            }
+            Action::Respawn => {
+                let spawn = self.spawn;
+                let (x_scale, y_scale) = self.spawn_scale;
+                if let Some(player) = player_id.and_then(|id| self.sprites.get_mut(&id)) {
+                    player.loc = spawn;
+                    player.velocity = Vector::new(0.0, 0.0);
+                    player.x_scale = x_scale;
+                    player.y_scale = y_scale;
+                    player.scale_tween = None;
+                    player.potion_timer = None;
+                    player.pending_potions.clear();
+                    player.active_potions.clear();
+                    player.gravity_scale = 1.0;
+                    player.jump_scale = 1.0;
+                    player.vx_slop = 0.0;
+                    player.vy_slop = 0.0;
+                }
+                // The terrain may have changed since the checkpoint, make room if it has
+                if let Some(id) = player_id.filter(|id| self.sprites.contains_key(id)) {
+                    if !self.settle_sprite(id) {
+                        let footprint = DestructionProfile {
+                            shape: DestructionShape::Footprint,
+                            ..DestructionProfile::default()
+                        };
+                        self.smash(id, footprint);
+                    }
+                }
+                self.underwater = 0.0;
+            }
+            Action::Win => {
+                if !self.done {
+                    self.done = true;
+                    let actions = self.on_win.clone();
+                    self.run_actions(&actions);
+                }
+            }
+            Action::Lose => {
+                // Nothing can undo a win
+                if !self.done {
+                    let actions = self.on_lose.clone();
+                    self.run_actions(&actions);
+                }
+            }
+            Action::ApplyPotion(effect) => {
+                if let Some(player) = player_id.and_then(|id| self.sprites.get_mut(&id)) {
+                    player.queue_potion(Potion::new(*effect));
+                }
+            }
+            Action::Spawn(name) => self.spawn(name),
+            Action::ExitLevel(exit) => self.exit = Some(exit.clone()),
+            Action::Checkpoint => {
+                if let Some(id) = player_id {
+                    let player = &self.sprites[&id];
+                    self.spawn = player.loc;
+                    // Respawning drops timed potions so they aren't part of the saved scale
+                    self.spawn_scale = self.scale_limits.clamp(player.base_scale());
+                    self.save_requested = true;
+                }
+            }
+            Action::Script {
+                script,
+                function,
+                this,
+            } => self.run_script(*script, function, *this),
+            Action::ShowText(text, seconds) => {
+                self.message = Some((text.clone(), *seconds));
+            }
+            Action::DestroyRegion {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                self.collision_map.remove_rect(*x, *y, *width, *height);
+                self.rubble_map.remove_rect(*x, *y, *width, *height);
+                self.foreground_map.remove_rect(*x, *y, *width, *height);
+                self.clear_colors(1, *x, *y, *width, *height);
+                self.clear_colors(2, *x, *y, *width, *height);
+                self.invalidate_rect(1, *x, *y, *width, *height);
+                self.invalidate_rect(2, *x, *y, *width, *height);
+                self.log_edit(1, *x, *y, *width, *height);
+                self.log_edit(2, *x, *y, *width, *height);
+                self.mark_sand_dirty(*x, *y, *width, *height);
             }
         }
-        self.collision_map.clear_sprite(sprite);
     }
 
-    fn add_foreground(&mut self, sprite: &Sprite) {
-        self.foreground_map.add_sprite(sprite);
-        for x in
-            sprite.loc.x as i32..sprite.loc.x as i32 + SPRITE_WIDTH as i32 * sprite.x_scale as i32
-        {
-            for y in sprite.loc.y as i32
-                ..sprite.loc.y as i32 + SPRITE_WIDTH as i32 * sprite.y_scale as i32
-            {
-                self.tile_cache
-                    .entry((x / TILE_SIZE as i32, y / TILE_SIZE as i32))
-                    .or_default()
-                    .2 = (None, None);
-                self.tile_queue
-                    .insert((2, x / TILE_SIZE as i32, y / TILE_SIZE as i32));
+    fn step_triggers(&mut self, fps: f32) {
+        if let Some((_, time)) = self.message.as_mut() {
+            *time -= 1.0 / fps;
+            if *time <= 0.0 {
+                self.message = None;
+            }
+        }
+        let player = match self.player_id() {
+            Some(id) => self.sprites[&id].area(),
+            None => return,
+        };
+        for (i, trigger) in self.triggers.iter_mut().enumerate() {
+            let inside = overlaps(&player, &trigger.area);
+            if inside != trigger.occupied {
+                trigger.occupied = inside;
+                self.events.push(if inside {
+                    SceneEvent::EnteredZone(i)
+                } else {
+                    SceneEvent::ExitedZone(i)
+                });
             }
         }
     }
 
-    fn add_background(&mut self, sprite: &Sprite) {
-        self.background_map.add_sprite(sprite);
-        for x in
-            sprite.loc.x as i32..sprite.loc.x as i32 + SPRITE_WIDTH as i32 * sprite.x_scale as i32
-        {
-            for y in sprite.loc.y as i32
-                ..sprite.loc.y as i32 + SPRITE_WIDTH as i32 * sprite.y_scale as i32
-            {
-                self.tile_cache
-                    .entry((x / TILE_SIZE as i32, y / TILE_SIZE as i32))
-                    .or_default()
-                    .0 = (None, None);
-                self.tile_queue
-                    .insert((0, x / TILE_SIZE as i32, y / TILE_SIZE as i32));
+    fn step_hazards(&mut self, fps: f32) {
+        // The finale drops the player past the world floor on purpose
+        if self.done || self.end_sequence_triggered {
+            return;
+        }
+        let player_id = match self.player_id() {
+            Some(id) => id,
+            None => return,
+        };
+        let player = &self.sprites[&player_id];
+        let area = player.area();
+        let scale = player.x_scale.max(player.y_scale);
+        let mut dead = player.loc.y > self.world_floor;
+        let mut in_water = false;
+        let mut breath = f32::INFINITY;
+        for hazard in &self.hazards {
+            if !overlaps(&area, &hazard.area) {
+                continue;
             }
+            match hazard.kind {
+                HazardKind::Kill => dead = true,
+                HazardKind::Spikes { safe_scale } => dead |= scale < safe_scale,
+                HazardKind::Water { breath: b } => {
+                    in_water = true;
+                    if area.y > hazard.area.y {
+                        breath = breath.min(b);
+                    }
+                }
+            }
+        }
+        if breath.is_finite() {
+            self.underwater += 1.0 / fps;
+            dead |= self.underwater > breath;
+        } else {
+            self.underwater = 0.0;
+        }
+        if in_water {
+            let player = self.sprites.get_mut(&player_id).unwrap();
+            player.velocity *= 0.9;
+        }
+        if dead {
+            self.run_actions(&[Action::Lose]);
         }
     }
 
-    fn step_physics(&mut self, camera: Vector, camera_scale: f32, fps: f32) {
-        let mut new_sprites = vec![];
-        for sprite in self.sprites.values_mut() {
-            if camera.distance(sprite.loc) > 1920.0 * camera_scale {
+    fn dispatch_events(&mut self) {
+        let events: Vec<_> = self.events.drain(..).collect();
+        for event in events {
+            let mut actions = vec![];
+            self.listeners.retain(|listener| {
+                if listener.event != event {
+                    return true;
+                }
+                actions.extend(listener.actions.iter().cloned());
+                !listener.once
+            });
+            self.run_actions(&actions);
+        }
+    }
+
+    // Characters turn to face the way they're moving, unless turning would
+    // push their collider into terrain
+    fn step_facing(&mut self) {
+        for character_id in self.characters.clone() {
+            let sprite = &self.sprites[&character_id];
+            let facing_left = if sprite.velocity.x < -0.05 {
+                true
+            } else if sprite.velocity.x > 0.05 {
+                false
+            } else {
+                continue;
+            };
+            if facing_left == sprite.facing_left {
+                continue;
+            }
+            let mut test = sprite.clone();
+            test.collider = mirror_mask(&sprite.collider);
+            if self.sprite_blocked(&test) {
                 continue;
             }
+            let sprite = self.sprites.get_mut(&character_id).unwrap();
+            sprite.collider = test.collider;
+            sprite.facing_left = facing_left;
+            self.sprite_cache.remove(&character_id);
+        }
+    }
 
-            if sprite.gravity {
-                sprite.velocity.y += 3.4 / fps;
+    fn step_animations(&mut self, fps: f32) {
+        for character_id in self.characters.clone() {
+            let sprite = &self.sprites[&character_id];
+            let state = if matches!(sprite.potion_timer, Some(t) if t > 0.0) {
+                AnimState::Drink
+            } else if !sprite.ground_contact {
+                if sprite.velocity.y < 0.0 {
+                    AnimState::Jump
+                } else {
+                    AnimState::Fall
+                }
+            } else if sprite.velocity.x.abs() > 0.05 {
+                AnimState::Walk
+            } else {
+                AnimState::Idle
+            };
+            let mut animator = match self.sprites.get_mut(&character_id).unwrap().animator.take() {
+                Some(animator) => animator,
+                None => continue,
+            };
+            if animator.step(state, fps) {
+                self.sprite_cache.remove(&character_id);
+                // Only take the new frame's collider if it doesn't push into terrain
+                let sprite = &self.sprites[&character_id];
+                let collider = match animator.collider() {
+                    Some(collider) if sprite.facing_left => Some(mirror_mask(collider)),
+                    collider => collider.cloned(),
+                };
+                if let Some(collider) = collider {
+                    if collider != sprite.collider {
+                        let mut test = sprite.clone();
+                        test.collider = collider;
+                        if !self.sprite_blocked(&test) {
+                            self.sprites.get_mut(&character_id).unwrap().collider = test.collider;
+                        }
+                    }
+                }
+            }
+            self.sprites.get_mut(&character_id).unwrap().animator = Some(animator);
+        }
+    }
+
+    fn step_particles(&mut self, camera: Vector, camera_scale: f32, fps: f32) {
+        let collision_map = &self.collision_map;
+        let rubble_map = &self.rubble_map;
+        let blocked = |p: &Particle, x: f32, y: f32| {
+            !rubble_map.check_rect(x as i32, y as i32, p.width, p.height)
+                && collision_map.check_rect(x as i32, y as i32, p.width, p.height)
+        };
+        // Debris falls and slides like a sprite its size would
+        let movement = &self.movement;
+        let mut settled = vec![];
+        let mut to_remove: IndexSet<usize> = IndexSet::default();
+        for (i, p) in self.particles.particles.iter_mut().enumerate() {
+            if p.loc.y > self.world_floor {
+                to_remove.insert(i);
+                continue;
+            }
+            if camera.distance(p.loc) > 1920.0 * camera_scale {
+                continue;
             }
+            let size = p.width.max(p.height) as f32;
+            p.velocity.y += movement.gravity.at(size) / fps;
+            let falling = p.velocity.y > 0.0;
             let mut blocked_y = false;
-            let mut in_rubble = false;
-            let falling = sprite.velocity.y > 0.0;
-            for (mut vx, mut vy) in vec![
-                (0, (sprite.velocity.y * sprite.y_scale as f32) as i32),
-                ((sprite.velocity.x * sprite.x_scale as f32) as i32, 0),
-            ] {
-                {
-                    let mut loc_x = sprite.loc.x;
-                    let mut loc_y = sprite.loc.y;
+            let mut dy = (p.velocity.y * p.height as f32) as i32;
+            while dy != 0 {
+                let y = p.loc.y + dy.signum() as f32;
+                if blocked(p, p.loc.x, y) {
+                    blocked_y = true;
+                    break;
+                }
+                p.loc.y = y;
+                dy -= dy.signum();
+            }
+            let mut dx = (p.velocity.x * p.width as f32) as i32;
+            while dx != 0 {
+                let x = p.loc.x + dx.signum() as f32;
+                if blocked(p, x, p.loc.y) {
+                    break;
+                }
+                p.loc.x = x;
+                dx -= dx.signum();
+            }
+            if !blocked_y {
+                if p.velocity.y.abs() >= 1.0 {
+                    p.ground_contact = false;
+                }
+            } else {
+                if falling {
+                    p.ground_contact = true;
+                }
+                p.velocity.y = 0.0;
+            }
+            if p.ground_contact {
+                let friction = movement.friction.at(size) / fps;
+                if p.velocity.x >= 0.0 {
+                    p.velocity.x = (p.velocity.x - friction).max(0.0);
+                } else {
+                    p.velocity.x = (p.velocity.x + friction).min(0.0);
+                }
+            }
+            if p.velocity.x.abs() > 1.0 || p.velocity.y.abs() > 1.0 {
+                p.sleep_timer = 0.0;
+            } else {
+                p.sleep_timer += 1.0 / fps;
+            }
+            if p.ground_contact && p.sleep_timer > 0.5 {
+                to_remove.insert(i);
+                settled.push(*p);
+            }
+        }
+        if !to_remove.is_empty() {
+            let mut i = 0;
+            self.particles.particles.retain(|_| {
+                i += 1;
+                !to_remove.contains(&(i - 1))
+            });
+        }
+        for p in settled {
+            let (x, y) = (p.loc.x as i32, p.loc.y as i32);
+            if p.width == 1 && p.height == 1 {
+                let _ = self.collision_map.insert(x, y);
+                let _ = self.rubble_map.insert(x, y);
+            } else {
+                let _ = self.collision_map.insert_rect(x, y, p.width, p.height);
+                let _ = self.rubble_map.insert_rect(x, y, p.width, p.height);
+            }
+            self.log_edit(1, x, y, p.width, p.height);
+            self.invalidate_rect(1, x, y, p.width, p.height);
+            self.touch_rubble(x, y, p.width, p.height);
+            self.mark_sand_dirty(x, y, p.width, p.height);
+        }
+        self.particles
+            .merge_distant(camera, 1920.0 * camera_scale * PARTICLE_LOD_DISTANCE);
+    }
 
-                    let step_x = (sprite.x_scale as f32 / 8.0)
-                        .min(1.0)
-                        .min(sprite.velocity.x.abs())
-                        .max(1.0)
-                        .copysign(sprite.velocity.x);
-                    let step_y = (sprite.y_scale as f32 / 8.0)
-                        .min(1.0)
-                        .min(sprite.velocity.y.abs())
-                        .max(1.0)
-                        .copysign(sprite.velocity.y);
+    fn step_bombs(&mut self, fps: f32) {
+        let mut exploding = vec![];
+        for (bomb_id, bomb) in &mut self.bombs {
+            let sprite = &self.sprites[&*bomb_id];
+            if bomb.contact && !bomb.lit {
+                for character_id in &self.characters {
+                    if self.sprites[character_id].overlap(sprite) {
+                        bomb.lit = true;
+                        break;
+                    }
+                }
+            }
+            if bomb.lit {
+                match bomb.fuse.as_mut() {
+                    Some(fuse) => {
+                        *fuse -= 1.0 / fps;
+                        if *fuse <= 0.0 {
+                            exploding.push(*bomb_id);
+                        }
+                    }
+                    None => exploding.push(*bomb_id),
+                }
+            }
+        }
+        for bomb_id in exploding {
+            let index = if let Some(i) = self.bombs.iter().position(|(id, _)| *id == bomb_id) {
+                i
+            } else {
+                continue;
+            };
+            let (_, bomb) = self.bombs.remove(index);
+            let sprite = self.sprites.remove(&bomb_id).unwrap();
+            self.sprite_cache.remove(&bomb_id);
+            self.explode(&sprite, &bomb);
+            self.events.push(SceneEvent::SpriteDestroyed(bomb_id));
+        }
+    }
 
-                    'outer: while vy.abs() >= 1 || vx.abs() >= 1 {
-                        if vy.abs() >= 1 {
-                            loc_y += step_y;
-                        } else {
-                            loc_x += step_x;
+    fn explode(&mut self, sprite: &Sprite, bomb: &Bomb) {
+        self.destroy(sprite, bomb.profile);
+        let center = sprite.loc + Vector::new(sprite.width() as f32, sprite.height() as f32) / 2.0;
+        let radius = sprite.width().max(sprite.height()) as f32 * bomb.profile.strength;
+        for (other_id, other) in self.sprites.iter_mut() {
+            if self.platforms.iter().any(|(id, _)| id == other_id) {
+                continue;
+            }
+            // Without gravity and ground friction nothing would ever slow a push down
+            if !other.gravity || other.gravity_scale <= 0.0 {
+                continue;
+            }
+            let other_center =
+                other.loc + Vector::new(other.width() as f32, other.height() as f32) / 2.0;
+            let d = center.distance(other_center);
+            if d >= radius {
+                continue;
+            }
+            let dir = if d > 0.0 {
+                (other_center - center) / d
+            } else {
+                Vector::new(0.0, -1.0)
+            };
+            // Velocity is in sprite pixels so the push in world space is
+            // proportional to each sprite's scale
+            other.velocity += dir * bomb.force * (1.0 - d / radius);
+            other.ground_contact = false;
+            other.sleep_timer = 0.0;
+        }
+        for p in &mut self.particles.particles {
+            let d = center.distance(p.loc);
+            if d < radius && d > 0.0 {
+                p.velocity += (p.loc - center) / d * bomb.force * (1.0 - d / radius);
+                p.ground_contact = false;
+                p.sleep_timer = 0.0;
+            }
+        }
+        // Chain reactions, other bombs caught in the blast go off shortly after
+        for (other_id, other) in &mut self.bombs {
+            let other_sprite = &self.sprites[&*other_id];
+            if other_sprite.loc.distance(sprite.loc) < radius {
+                other.lit = true;
+                other.fuse = Some(other.fuse.unwrap_or(0.0).min(0.1));
+            }
+        }
+    }
+
+    fn smash(&mut self, sprite_id: usize, profile: DestructionProfile) {
+        let sprite = self.sprites[&sprite_id].clone();
+        self.destroy(&sprite, profile);
+    }
+
+    fn invalidate_rect(&mut self, layer: u32, x: i32, y: i32, width: u32, height: u32) {
+        for xx in x / TILE_SIZE as i32..(x + width as i32) / TILE_SIZE as i32 + 1 {
+            for yy in y / TILE_SIZE as i32..(y + height as i32) / TILE_SIZE as i32 + 1 {
+                let cached = self.tile_cache.entry((xx, yy)).or_default();
+                match layer {
+                    0 => cached.0 = (None, None),
+                    1 => cached.1 = (None, None),
+                    _ => cached.2 = (None, None),
+                }
+                self.tile_queue.insert((layer, xx, yy));
+            }
+        }
+    }
+
+    fn hardness_at(&self, x: i32, y: i32) -> f32 {
+        let mut hardness = DEFAULT_HARDNESS;
+        for (region, h) in &self.materials {
+            if region.contains_point(vek::Vec2::new(x, y)) {
+                hardness = hardness.max(*h);
+            }
+        }
+        hardness
+    }
+
+    // Carve a profile out of the foreground and terrain, using the sprite's
+    // location and scale as the origin and grid
+    fn destroy(&mut self, sprite: &Sprite, profile: DestructionProfile) {
+        let cx = sprite.loc.x + sprite.width() as f32 / 2.0;
+        let cy = sprite.loc.y + sprite.height() as f32 / 2.0;
+        let radius = sprite.width().max(sprite.height()) as f32 * 0.5 * profile.strength;
+        let w = sprite.collider.width as i32;
+        let h = sprite.collider.height as i32;
+        let reach = ((profile.strength - 1.0).max(0.0) * w.max(h) as f32 * 0.5).ceil() as i32;
+
+        // Each cell is (dx, dy, distance from the origin normalized to the profile's extent)
+        let cells: Vec<(i32, i32, f32)> = match profile.shape {
+            // Shifted up a row so growing clears headroom without digging into the floor
+            DestructionShape::Circle => (-reach..w + reach)
+                .flat_map(|dx| (-reach - 1..h + reach - 1).map(move |dy| (dx, dy)))
+                .filter_map(|(dx, dy)| {
+                    let x = sprite.loc.x + (dx as f32 + 0.5) * sprite.x_scale;
+                    let y = sprite.loc.y + (dy as f32 + 0.5) * sprite.y_scale;
+                    let d = Vector::new(cx, cy).distance(Vector::new(x, y));
+                    if d < radius {
+                        Some((dx, dy, d / radius))
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            DestructionShape::Column => (-reach..w + reach)
+                .flat_map(|dx| (-h * 10 - reach..h + reach).map(move |dy| (dx, dy)))
+                .filter_map(|(dx, dy)| {
+                    let x = sprite.loc.x + (dx as f32 + 0.5) * sprite.x_scale;
+                    let y = sprite.loc.y + (dy as f32 + 0.5) * sprite.y_scale;
+                    let d = Vector::new(cx, cy).distance(Vector::new(x, y));
+                    if d < radius {
+                        Some((dx, dy, d / radius))
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            DestructionShape::Cone { angle, spread } => {
+                let reach = (profile.strength * w.max(h) as f32).ceil() as i32;
+                (-reach..w + reach)
+                    .flat_map(|dx| (-reach..h + reach).map(move |dy| (dx, dy)))
+                    .filter_map(|(dx, dy)| {
+                        let x = sprite.loc.x + (dx as f32 + 0.5) * sprite.x_scale;
+                        let y = sprite.loc.y + (dy as f32 + 0.5) * sprite.y_scale;
+                        let d = Vector::new(cx, cy).distance(Vector::new(x, y));
+                        let mut off = (y - cy).atan2(x - cx) - angle;
+                        while off > std::f32::consts::PI {
+                            off -= std::f32::consts::PI * 2.0;
                         }
-                        for dx in 0..SPRITE_WIDTH {
-                            for dy in 0..SPRITE_WIDTH {
-                                let i = dx + dy * SPRITE_WIDTH;
-                                if sprite.collider[i] {
-                                    let x = loc_x as i32 + dx as i32 * sprite.x_scale as i32;
-                                    let y = loc_y as i32 + dy as i32 * sprite.y_scale as i32;
-                                    if self.rubble_map.check_rect(
-                                        x,
-                                        y,
-                                        sprite.x_scale,
-                                        sprite.y_scale,
-                                    ) {
-                                        in_rubble = true;
-                                    } else if self.collision_map.check_rect(
-                                        x,
-                                        y,
-                                        sprite.x_scale,
-                                        sprite.y_scale,
-                                    ) {
-                                        if vy.abs() >= 1 {
-                                            blocked_y = true;
-                                        }
-                                        break 'outer;
-                                    }
-                                }
-                            }
+                        while off < -std::f32::consts::PI {
+                            off += std::f32::consts::PI * 2.0;
                         }
-                        if vy.abs() >= 1 {
-                            vy -= step_y as i32;
+                        // The sprite's own body is always cleared so it isn't left embedded
+                        if d < radius / profile.strength.max(1.0)
+                            || (d < radius * 2.0 && off.abs() < spread)
+                        {
+                            Some((dx, dy, d / (radius * 2.0)))
                         } else {
-                            vx -= step_x as i32;
+                            None
                         }
-                        sprite.loc.x = loc_x;
-                        sprite.loc.y = loc_y;
-                    }
-                }
+                    })
+                    .collect()
             }
-            if sprite.is_player && !in_rubble {
-                self.rubble_map.clear();
+            DestructionShape::Footprint => (0..w)
+                .flat_map(|dx| (0..h).map(move |dy| (dx, dy)))
+                .filter(|(dx, dy)| sprite.collider.get(*dx as usize, *dy as usize))
+                .map(|(dx, dy)| (dx, dy, 0.0))
+                .collect(),
+            DestructionShape::GroundPound { depth } => {
+                let depth = (depth * profile.strength * h as f32).ceil().max(1.0) as i32;
+                (0..w)
+                    .flat_map(|dx| (h..h + depth).map(move |dy| (dx, dy)))
+                    .map(|(dx, dy)| (dx, dy, (dy - h) as f32 / depth as f32))
+                    .collect()
             }
-            if !blocked_y {
-                if sprite.velocity.y.abs() >= 1.0 {
-                    sprite.ground_contact = false;
-                }
-            } else {
-                if falling {
-                    sprite.ground_contact = true;
-                    sprite.jumping = false;
-                }
-                sprite.velocity.y = 0.0;
+        };
+
+        for (dx, dy, n) in cells {
+            let (x, y, w, h) = sprite.pixel_rect(dx, dy);
+            let power = profile.strength * (1.0 - profile.falloff * n);
+            if power * (1.0 + cell_noise(x, y)) < self.hardness_at(x, y) {
+                continue;
             }
-            if sprite.ground_contact {
-                if sprite.velocity.x >= 0.0 {
-                    sprite.velocity.x = (sprite.velocity.x - 1.0 / fps).max(0.0);
-                } else {
-                    sprite.velocity.x = (sprite.velocity.x + 1.0 / fps).min(0.0);
-                }
+            if self.foreground_map.remove_rect(x, y, w, h).1 > 0 {
+                self.clear_colors(2, x, y, w, h);
+                self.invalidate_rect(2, x, y, w, h);
+                self.log_edit(2, x, y, w, h);
             }
-            if sprite.velocity.x.abs() > 1.0 || sprite.velocity.y.abs() > 1.0 {
-                sprite.sleep_timer = 0.0;
-            } else {
-                sprite.sleep_timer += 1.0 / fps;
+            if self.collision_map.remove_rect(x, y, w, h).1 > 0 {
+                self.clear_colors(1, x, y, w, h);
+                self.log_edit(1, x, y, w, h);
+                let a = (cy - y as f32).atan2(cx - x as f32);
+                self.particles.spawn(
+                    Vector::new(x as f32, y as f32),
+                    Vector::new(a.cos() * -0.5, a.sin() * -0.5),
+                    w,
+                    h,
+                );
+                self.invalidate_rect(1, x, y, w, h);
+                self.mark_sand_dirty(x, y, w, h);
             }
-            if sprite.is_player {
-                //&& sprite.y_scale < 80 {
-                // Collision resolution
-                let mut x_dir = 0;
-                let mut y_dir = 0;
-                for dx in 0..SPRITE_WIDTH {
-                    for dy in 0..SPRITE_WIDTH {
-                        let i = dx + dy * SPRITE_WIDTH;
-                        if sprite.collider[i] {
-                            let x = sprite.loc.x as i32 + dx as i32 * sprite.x_scale as i32;
-                            let y = sprite.loc.y as i32 + dy as i32 * sprite.y_scale as i32;
-                            if self
-                                .rubble_map
-                                .check_rect(x, y, sprite.x_scale, sprite.y_scale)
-                            {
-                            } else if self.collision_map.check_rect(
-                                x,
-                                y,
-                                sprite.x_scale,
-                                sprite.y_scale,
-                            ) {
-                                if dx <= SPRITE_WIDTH / 2 {
-                                    x_dir += 1;
-                                } else {
-                                    x_dir -= 1;
-                                }
-                                if dy <= SPRITE_WIDTH / 2 {
-                                    y_dir += 1;
-                                } else {
-                                    y_dir -= 1;
-                                }
-                            }
-                        }
-                    }
-                }
-                sprite.loc.x += (x_dir.max(-1).min(1) * sprite.x_scale as i32) as f32;
-                sprite.loc.y += (y_dir.max(-1).min(1) * sprite.y_scale as i32) as f32;
+        }
+    }
+
+    // Keep the rubble in a rect passable for a while longer
+    fn touch_rubble(&mut self, x: i32, y: i32, width: u32, height: u32) {
+        let leaf = COLLISION_MAP_LEAF_SIZE as i32;
+        for rx in x.div_euclid(leaf)..=(x + width as i32 - 1).div_euclid(leaf) {
+            for ry in y.div_euclid(leaf)..=(y + height as i32 - 1).div_euclid(leaf) {
+                self.rubble_timers.insert((rx, ry), RUBBLE_DECAY_TIME);
             }
         }
+    }
 
-        let mut to_remove: IndexSet<usize> = IndexSet::default();
-        for particle_id in &self.particles {
-            let sprite = &self.sprites[particle_id];
-            if sprite.loc.y > 30000.0 {
-                to_remove.insert(*particle_id);
-                self.sprite_cache.remove(particle_id);
+    // Rubble sets solid region by region once nothing has been inside it for
+    // a while, so one sprite climbing out can't entomb another that's still digging
+    fn step_rubble(&mut self, camera: Vector, camera_scale: f32, fps: f32) {
+        if self.rubble_timers.is_empty() {
+            return;
+        }
+        let radius = 1920.0 * camera_scale;
+        let mut occupied = vec![];
+        for sprite in self.sprites.values() {
+            let size = sprite.width().max(sprite.height()) as f32;
+            if camera.distance(sprite.loc) > radius + size {
                 continue;
             }
-            if sprite.ground_contact && sprite.sleep_timer > 0.5 {
-                to_remove.insert(*particle_id);
-                for x in 0..SPRITE_WIDTH {
-                    for y in 0..SPRITE_WIDTH {
-                        let i = x + y * SPRITE_WIDTH;
-                        if sprite.collider[i as usize] {
-                            for dx in 0..sprite.x_scale {
-                                for dy in 0..sprite.y_scale {
-                                    let x = sprite.loc.x as i32
-                                        + x as i32 * sprite.x_scale as i32
-                                        + dx as i32;
-                                    let y = sprite.loc.y as i32
-                                        + y as i32 * sprite.y_scale as i32
-                                        + dy as i32;
-                                    self.collision_map.insert(x, y).unwrap();
-                                    self.rubble_map.insert(x, y).unwrap();
-                                    self.tile_cache
-                                        .entry((x / TILE_SIZE as i32, y / TILE_SIZE as i32))
-                                        .or_default()
-                                        .1 = (None, None);
-                                    self.tile_queue.insert((
-                                        1,
-                                        x / TILE_SIZE as i32,
-                                        y / TILE_SIZE as i32,
-                                    ));
-                                }
-                            }
+            for dx in 0..sprite.collider.width {
+                for dy in 0..sprite.collider.height {
+                    if sprite.collider.get(dx, dy) {
+                        let (x, y, w, h) = sprite.pixel_rect(dx as i32, dy as i32);
+                        if self.rubble_map.check_rect(x, y, w, h) {
+                            occupied.push((x, y, w, h));
                         }
                     }
                 }
             }
         }
-        self.particles.retain(|pid| !to_remove.contains(pid));
-        self.sprites.retain(|pid, _| !to_remove.contains(pid));
+        for (x, y, width, height) in occupied {
+            self.touch_rubble(x, y, width, height);
+        }
 
-        let mut drinkers = vec![];
-        let mut consumed: IndexSet<usize> = IndexSet::default();
-        let mut collected: IndexSet<usize> = IndexSet::default();
-        let mut start_end = false;
-        for character_id in &self.characters {
-            let character = &self.sprites[character_id];
-            for (potion_id, potion_type, end) in &self.potions {
-                if consumed.contains(potion_id) {
-                    continue;
-                }
-                let potion = &self.sprites[potion_id];
-                if character.overlap(potion) {
-                    consumed.insert(*potion_id);
-                    drinkers.push((*character_id, *potion_type));
-                    start_end |= *end;
-                }
+        let leaf = COLLISION_MAP_LEAF_SIZE as i32;
+        let mut expired = vec![];
+        for (region, timer) in self.rubble_timers.iter_mut() {
+            // Sprites further out aren't checked above, so rubble out there waits until
+            // the camera comes back rather than dropping whatever is standing on it
+            let center = Vector::new(
+                (region.0 as f32 + 0.5) * leaf as f32,
+                (region.1 as f32 + 0.5) * leaf as f32,
+            );
+            if camera.distance(center) > radius - leaf as f32 {
+                continue;
             }
-            for collectable_id in &self.collectables {
-                if collected.contains(collectable_id) {
-                    continue;
-                }
-                let collectable = &self.sprites[collectable_id];
-                if character.overlap(collectable) {
-                    if collectable.x_scale > 30 {
-                        self.done = true;
-                    }
-                    collected.insert(*collectable_id);
-                }
+            *timer -= 1.0 / fps;
+            if *timer <= 0.0 {
+                expired.push(*region);
             }
         }
-        for potion_id in consumed {
-            self.potions.retain(|(id, _, _)| *id != potion_id);
-            self.sprites.remove(&potion_id);
-            self.sprite_cache.remove(&potion_id);
+        for (rx, ry) in expired {
+            self.rubble_timers.remove(&(rx, ry));
+            self.rubble_map
+                .remove_rect(rx * leaf, ry * leaf, leaf as u32, leaf as u32);
         }
-        if start_end {
-            self.end_sequence_triggered = true;
-            self.potions
-                .iter_mut()
-                .for_each(|(_, pt, _)| *pt = PotionType::Relative(10, 10));
+    }
+
+    // Wake up the rubble around a rect, including the region above it since
+    // anything resting there may have lost its support
+    fn mark_sand_dirty(&mut self, x: i32, y: i32, width: u32, height: u32) {
+        if !self.falling_sand {
+            return;
         }
-        for collectable_id in collected {
-            self.collectables.retain(|id| *id != collectable_id);
-            self.collected.insert(
-                collectable_id,
-                self.sprites.remove(&collectable_id).unwrap(),
-            );
-            self.score += 1;
+        let leaf = COLLISION_MAP_LEAF_SIZE as i32;
+        for rx in (x - 1).div_euclid(leaf)..=(x + width as i32).div_euclid(leaf) {
+            for ry in (y - leaf).div_euclid(leaf)..=(y + height as i32).div_euclid(leaf) {
+                self.sand_dirty.insert((rx, ry));
+            }
         }
-        for (sprite_id, potion_type) in drinkers {
-            let sprite = self.sprites.get_mut(&sprite_id).unwrap();
-            let timer = sprite.potion_timer.get_or_insert(SCALE_CHANGE_TIMEOUT);
-            if *timer <= 0.0 {
-                *timer = SCALE_CHANGE_TIMEOUT;
+    }
+
+    // Where a loose grain at (x, y) wants to go, either straight down or
+    // sliding off the side of a pile that's steeper than the angle of repose
+    fn sand_target(&self, x: i32, y: i32, run: i32, drop: i32, flip: bool) -> Option<(i32, i32)> {
+        if !self.collision_map.check_point(x, y + 1) {
+            return Some((x, y + 1));
+        }
+        let dirs = if flip { [1, -1] } else { [-1, 1] };
+        for d in &dirs {
+            let nx = x + d * run;
+            if (1..=run).any(|i| self.collision_map.check_point(x + d * i, y)) {
+                continue;
+            }
+            if (1..=drop).all(|i| !self.collision_map.check_point(nx, y + i)) {
+                return Some((nx, y + 1));
             }
-            sprite.pending_potions.push(potion_type);
         }
+        None
+    }
 
-        for character_id in self.characters.clone() {
-            let sprite = self.sprites.get_mut(&character_id).unwrap();
-            if let Some(time) = sprite.potion_timer.as_mut() {
-                *time -= 1.0 / fps;
-                if *time > 0.0 && !self.final_potion_triggered {
-                    continue;
-                }
-                if *time < -1.0 {
-                    sprite.potion_timer.take();
-                }
-                let mut x_scale = sprite.x_scale as i32;
-                let mut y_scale = sprite.y_scale as i32;
-                for potion in sprite.pending_potions.drain(..) {
-                    match potion {
-                        PotionType::Relative(dx, dy) => {
-                            x_scale += dx;
-                            y_scale += dy;
-                        }
-                        PotionType::Absolute(x, y) => {
-                            if let Some(x) = x {
-                                x_scale = x;
-                            }
-                            if let Some(y) = y {
-                                y_scale = y;
-                            }
-                        }
-                    }
-                }
-                if self.end_sequence_triggered {
-                    self.final_potion_triggered = true;
-                }
+    fn step_sand(&mut self) {
+        if !self.falling_sand || self.sand_dirty.is_empty() {
+            return;
+        }
+        let slope = self.sand_repose.tan().max(0.01);
+        let run = (1.0 / slope).round().max(1.0) as i32;
+        let drop = slope.round().max(1.0) as i32;
+        let leaf = COLLISION_MAP_LEAF_SIZE as i32;
+        self.sand_tick = self.sand_tick.wrapping_add(1);
+        let flip = self.sand_tick & 1 == 0;
 
-                let x_delta;
-                let y_delta;
-                if self.final_potion_triggered {
-                    x_delta = 20;
-                    y_delta = 20;
-                } else {
-                    x_delta = x_scale.max(0).min(MAX_SCALE as i32) - sprite.x_scale as i32;
-                    y_delta = y_scale.max(0).min(MAX_SCALE as i32) - sprite.y_scale as i32;
-                }
-                if x_delta == 0 && y_delta == 0 {
-                    continue;
-                }
-                let initial_width = SPRITE_WIDTH as u32 * sprite.x_scale;
-                let initial_height = SPRITE_WIDTH as u32 * sprite.y_scale;
-                sprite.x_scale = (sprite.x_scale as i32 + x_delta)
-                    .max(1)
-                    .min(MAX_SCALE as i32) as u32;
-                sprite.y_scale = (sprite.y_scale as i32 + y_delta)
-                    .max(1)
-                    .min(MAX_SCALE as i32) as u32;
-                sprite.loc.x -=
-                    (SPRITE_WIDTH as f32 * sprite.x_scale as f32 - initial_width as f32) / 2.0;
-                sprite.loc.y -= SPRITE_WIDTH as f32 * sprite.y_scale as f32 - initial_height as f32;
-                //FIXME: Why is this offset necessary?
-                sprite.loc.y -= 8.0;
-                if x_delta > 0 || y_delta > 0 {
-                    let cx = sprite.loc.x + (SPRITE_WIDTH * sprite.x_scale as usize) as f32 / 2.0;
-                    let cy = sprite.loc.y + (SPRITE_WIDTH * sprite.y_scale as usize) as f32 / 2.0;
-                    let shape: Vec<_> = if sprite.y_scale < MAX_SCALE as u32 {
-                        (0..SPRITE_WIDTH as i32)
-                            .flat_map(|x| (-1..SPRITE_WIDTH as i32 - 1).map(move |y| (x, y)))
-                            .collect()
+        let count = self.sand_dirty.len().min(SAND_REGIONS_PER_STEP);
+        let regions: Vec<(i32, i32)> = self.sand_dirty.drain(..count).collect();
+        for (rx, ry) in regions {
+            if !self
+                .rubble_map
+                .check_rect(rx * leaf, ry * leaf, leaf as u32, leaf as u32)
+            {
+                continue;
+            }
+            let mut moved = false;
+            // Bottom up so a grain only moves once per step
+            for y in (ry * leaf..(ry + 1) * leaf).rev() {
+                for i in 0..leaf {
+                    let x = if flip {
+                        rx * leaf + i
                     } else {
-                        (-(SPRITE_WIDTH as i32) * 10..SPRITE_WIDTH as i32)
-                            .flat_map(|y| (0..SPRITE_WIDTH as i32).map(move |x| (x, y)))
-                            .collect()
+                        (rx + 1) * leaf - 1 - i
                     };
-                    for (dx, dy) in shape {
-                        if true {
-                            let x = sprite.loc.x as i32 + dx as i32 * sprite.x_scale as i32;
-                            let y = sprite.loc.y as i32 + dy as i32 * sprite.y_scale as i32;
-                            if Vector::new(cx, cy).distance(Vector::new(x as f32, y as f32))
-                                < SPRITE_WIDTH as f32
-                                    * sprite.x_scale.max(sprite.y_scale) as f32
-                                    * 0.5
-                            {
-                                if self
-                                    .foreground_map
-                                    .remove_rect(x, y, sprite.x_scale, sprite.y_scale)
-                                    .1
-                                    > 0
-                                {
-                                    for xx in (sprite.loc.x as i32
-                                        + dx as i32 * sprite.x_scale as i32)
-                                        / TILE_SIZE as i32
-                                        ..(sprite.loc.x as i32
-                                            + (dx + 1) as i32 * sprite.x_scale as i32)
-                                            / TILE_SIZE as i32
-                                    {
-                                        for yy in (sprite.loc.y as i32
-                                            + dy as i32 * sprite.y_scale as i32)
-                                            / TILE_SIZE as i32
-                                            ..(sprite.loc.y as i32
-                                                + (dy + 1) as i32 * sprite.y_scale as i32)
-                                                / TILE_SIZE as i32
-                                        {
-                                            let cached =
-                                                self.tile_cache.entry((xx, yy)).or_default();
-                                            cached.2 = (None, None);
-                                            self.tile_queue.insert((2, xx, yy));
-                                        }
-                                    }
-                                }
-                                if self
-                                    .collision_map
-                                    .remove_rect(x, y, sprite.x_scale, sprite.y_scale)
-                                    .1
-                                    > 0
-                                {
-                                    if new_sprites.len() + self.particles.len() < 300 {
-                                        let mut collider = [false; SPRITE_WIDTH * SPRITE_WIDTH];
-                                        collider[0] = true;
-                                        let mut new_sprite = Sprite::from_collider(
-                                            collider,
-                                            x as f32,
-                                            y as f32,
-                                            sprite.x_scale,
-                                            sprite.y_scale,
-                                            TERRAIN_COLOR,
-                                        );
-                                        let a = (cy - y as f32).atan2(cx - x as f32);
-                                        new_sprite.velocity =
-                                            Vector::new(a.cos() * -0.5, a.sin() * -0.5);
-                                        new_sprites.push(new_sprite);
-                                    }
-                                    for xx in (sprite.loc.x as i32
-                                        + dx as i32 * sprite.x_scale as i32)
-                                        / TILE_SIZE as i32
-                                        ..(sprite.loc.x as i32
-                                            + (dx + 1) as i32 * sprite.x_scale as i32)
-                                            / TILE_SIZE as i32
-                                            + 1
-                                    {
-                                        for yy in (sprite.loc.y as i32
-                                            + dy as i32 * sprite.y_scale as i32)
-                                            / TILE_SIZE as i32
-                                            ..(sprite.loc.y as i32
-                                                + (dy + 1) as i32 * sprite.y_scale as i32)
-                                                / TILE_SIZE as i32
-                                                + 1
-                                        {
-                                            let cached =
-                                                self.tile_cache.entry((xx, yy)).or_default();
-                                            cached.1 = (None, None);
-                                            self.tile_queue.insert((1, xx, yy));
-                                        }
-                                    }
-                                }
-                            }
-                        }
+                    if !self.rubble_map.check_point(x, y) || !self.collision_map.check_point(x, y) {
+                        continue;
+                    }
+                    if let Some((nx, ny)) = self.sand_target(x, y, run, drop, flip) {
+                        self.collision_map.remove_rect(x, y, 1, 1);
+                        self.rubble_map.remove_rect(x, y, 1, 1);
+                        self.clear_colors(1, x, y, 1, 1);
+                        let _ = self.collision_map.insert(nx, ny);
+                        let _ = self.rubble_map.insert(nx, ny);
+                        self.log_edit(1, x, y, 1, 1);
+                        self.log_edit(1, nx, ny, 1, 1);
+                        self.invalidate_rect(1, x - run, y, run as u32 * 2 + 1, 2);
+                        self.sand_dirty
+                            .insert((nx.div_euclid(leaf), ny.div_euclid(leaf)));
+                        self.touch_rubble(nx, ny, 1, 1);
+                        moved = true;
+                    }
+                }
+            }
+            if moved {
+                self.mark_sand_dirty(rx * leaf, ry * leaf, leaf as u32, leaf as u32);
+            }
+        }
+    }
+
+    fn sprite_blocked(&self, sprite: &Sprite) -> bool {
+        for dx in 0..sprite.collider.width {
+            for dy in 0..sprite.collider.height {
+                if sprite.collider.get(dx, dy) {
+                    let (x, y, w, h) = sprite.pixel_rect(dx as i32, dy as i32);
+                    if !self.rubble_map.check_rect(x, y, w, h)
+                        && self.collision_map.check_rect(x, y, w, h)
+                    {
+                        return true;
                     }
                 }
             }
         }
-        if !new_sprites.is_empty() {
-            //self.rubble_map.clear();
+        false
+    }
+
+    // Nudge a sprite out of terrain, preferring to move up, returns false if it's stuck
+    fn settle_sprite(&mut self, sprite_id: usize) -> bool {
+        if let Some(loc) = self.free_spot(&self.sprites[&sprite_id]) {
+            self.sprites.get_mut(&sprite_id).unwrap().loc = loc;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn fits_at_scale(&self, sprite_id: usize, scale: (f32, f32), anchor: ScaleAnchor) -> bool {
+        let mut sprite = self.sprites[&sprite_id].clone();
+        sprite.resize(scale.0, scale.1, anchor);
+        self.free_spot(&sprite).is_some()
+    }
+
+    // The biggest scale between from and to that the sprite has room for
+    fn largest_fit(
+        &self,
+        sprite_id: usize,
+        from: (f32, f32),
+        to: (f32, f32),
+        anchor: ScaleAnchor,
+    ) -> (f32, f32) {
+        // Steps of a quarter scale are fine enough that the gap isn't noticeable
+        let steps = ((to.0 - from.0).abs().max((to.1 - from.1).abs()) * 4.0).ceil() as i32;
+        let mut best = from;
+        for i in 1..=steps {
+            let t = i as f32 / steps as f32;
+            let scale = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+            if !self.fits_at_scale(sprite_id, scale, anchor) {
+                break;
+            }
+            best = scale;
+        }
+        best
+    }
+
+    fn free_spot(&self, sprite: &Sprite) -> Option<Vector> {
+        if !self.sprite_blocked(sprite) {
+            return Some(sprite.loc);
+        }
+        let mut sprite = sprite.clone();
+        let start = sprite.loc;
+        for k in 1..=sprite.collider.width.max(sprite.collider.height) as i32 / 2 {
+            let up = (k as f32 * sprite.y_scale).ceil();
+            let side = (k as f32 * sprite.x_scale).ceil();
+            for offset in &[
+                Vector::new(0.0, -up),
+                Vector::new(-side, 0.0),
+                Vector::new(side, 0.0),
+                Vector::new(-side, -up),
+                Vector::new(side, -up),
+            ] {
+                sprite.loc = start + *offset;
+                if !self.sprite_blocked(&sprite) {
+                    return Some(sprite.loc);
+                }
+            }
+        }
+        None
+    }
+
+    fn step_scale_tween(&mut self, sprite_id: usize, fps: f32) {
+        let sprite = self.sprites.get_mut(&sprite_id).unwrap();
+        let mut tween = if let Some(tween) = sprite.scale_tween.take() {
+            tween
+        } else {
+            return;
+        };
+        tween.elapsed += 1.0 / fps;
+        let t = if tween.duration > 0.0 {
+            (tween.elapsed / tween.duration).min(1.0)
+        } else {
+            1.0
+        };
+        // Smoothstep so the change eases in and out
+        let e = t * t * (3.0 - 2.0 * t);
+        let (x_scale, y_scale) = self.scale_limits.clamp((
+            tween.from.0 + (tween.to.0 - tween.from.0) * e,
+            tween.from.1 + (tween.to.1 - tween.from.1) * e,
+        ));
+        let (from, planned, anchor, growth, destruction) = (
+            tween.from,
+            tween.planned,
+            tween.anchor,
+            tween.growth,
+            tween.destruction,
+        );
+        let mut timed = vec![];
+        if t < 1.0 {
+            sprite.scale_tween = Some(tween);
+        } else {
+            timed = tween.timed;
+        }
+        let previous = (sprite.x_scale, sprite.y_scale, sprite.loc);
+        let grew = x_scale > sprite.x_scale || y_scale > sprite.y_scale;
+        if (x_scale, y_scale) != (sprite.x_scale, sprite.y_scale) {
+            sprite.resize(x_scale, y_scale, anchor);
+        }
+        if (grew && growth == GrowthPolicy::Smash) || self.final_potion_triggered {
+            // At full size growth smashes the same way, whatever the potion said
+            let profile = if self.scale_limits.at_max(&self.sprites[&sprite_id]) {
+                DestructionProfile {
+                    shape: DestructionShape::Column,
+                    ..destruction
+                }
+            } else {
+                destruction
+            };
+            self.smash(sprite_id, profile);
         }
-        for sprite in new_sprites {
-            self.add_particle(sprite);
+        let settled = self.settle_sprite(sprite_id);
+        let sprite = self.sprites.get_mut(&sprite_id).unwrap();
+        if !settled {
+            // No room at this size, stay at the last one that fit
+            sprite.x_scale = previous.0;
+            sprite.y_scale = previous.1;
+            sprite.loc = previous.2;
+            if let Some(aborted) = sprite.scale_tween.take() {
+                timed = aborted.timed;
+            }
         }
+        // Finished or cut short, the potions revert only as far as the tween got
+        let reached = (sprite.x_scale, sprite.y_scale);
+        sprite.activate_scale_potions(timed, from, planned, reached);
     }
 
     fn draw(&mut self, gfx: &mut Graphics, x: i32, y: i32, width: u32, height: u32, scale: f32) {
@@ -1308,24 +4251,57 @@ impl Scene {
             }
         }
 
+        for p in &self.particles.particles {
+            let sx = p.loc.x - x as f32;
+            let sy = p.loc.y - y as f32;
+            if sx > -(p.width as f32)
+                && sx < width as f32
+                && sy > -(p.height as f32)
+                && sy < height as f32
+            {
+                gfx.fill_rect(
+                    &Rectangle::new(
+                        Vector::new((sx / scale).floor(), (sy / scale).floor()),
+                        Vector::new(
+                            (p.width as f32 / scale).ceil(),
+                            (p.height as f32 / scale).ceil(),
+                        ),
+                    ),
+                    TERRAIN_COLOR,
+                );
+            }
+        }
+
         for (sprite_id, sprite) in &self.sprites {
             let sx = sprite.loc.x - x as f32;
             let sy = sprite.loc.y - y as f32;
-            let w = (SPRITE_WIDTH as u32 * sprite.x_scale) as f32;
-            let h = (SPRITE_WIDTH as u32 * sprite.y_scale) as f32;
+            let w = sprite.width() as f32;
+            let h = sprite.height() as f32;
             if sx > -w && sx < width as f32 && sy > -h && sy < height as f32 {
                 if !self.sprite_cache.contains_key(sprite_id) {
                     self.sprite_cache.insert(*sprite_id, sprite.image(gfx));
                 }
                 let sprite_image = &self.sprite_cache[sprite_id];
-                let region = Rectangle::new(
+                let mut region = Rectangle::new(
                     Vector::new(
                         ((sprite.loc.x as i32 - x) as f32 / scale).floor(),
                         ((sprite.loc.y as i32 - y) as f32 / scale).floor(),
                     ),
                     Vector::new((w / scale).ceil(), (h / scale).ceil()),
                 );
+                if let Some(t) = sprite.blocked_timer {
+                    // Shake to show that there wasn't room to grow
+                    region.pos.x += ((t * 60.0).sin() * 3.0).round();
+                }
                 gfx.draw_image(sprite_image, region);
+                if let Some((_, bomb)) = self.bombs.iter().find(|(id, _)| id == sprite_id) {
+                    if bomb.lit && (bomb.fuse.unwrap_or(0.0) * 10.0).sin() > 0.0 {
+                        gfx.draw_image_tinted(sprite_image, region, Color::RED);
+                    }
+                }
+                if sprite.blocked_timer.is_some() {
+                    gfx.draw_image_tinted(sprite_image, region, Color::from_rgba(80, 80, 80, 0.6));
+                }
                 if let Some(t) = sprite.potion_timer {
                     if t > 0.0 {
                         let red_shift: u8 = ((t
@@ -1333,11 +4309,12 @@ impl Scene {
                                 .sin()
                             + 1.0)
                             * 255.0) as u8;
-                        let mut pixels = [0; SPRITE_WIDTH * SPRITE_WIDTH * 4];
-                        for x in 0..SPRITE_WIDTH {
-                            for y in 0..SPRITE_WIDTH {
-                                let i = x + y * SPRITE_WIDTH as usize;
-                                if sprite.collider[i] {
+                        let mask = &sprite.collider;
+                        let mut pixels = vec![0; mask.width * mask.height * 4];
+                        for x in 0..mask.width {
+                            for y in 0..mask.height {
+                                let i = x + y * mask.width;
+                                if mask.get(x, y) {
                                     pixels[i * 4] = red_shift;
                                     pixels[i * 4 + 1] = 0xff;
                                     pixels[i * 4 + 1] = 0xff;
@@ -1348,8 +4325,8 @@ impl Scene {
                         let overlay = Image::from_raw(
                             gfx,
                             Some(&pixels),
-                            SPRITE_WIDTH as u32,
-                            SPRITE_WIDTH as u32,
+                            mask.width as u32,
+                            mask.height as u32,
                             PixelFormat::RGBA,
                         )
                         .unwrap();
@@ -1370,85 +4347,469 @@ impl Scene {
     }
 }
 
-enum TerrainChunk {
-    Foreground(Sprite),
-    Background(Sprite),
-    Terrain(Sprite),
+enum TerrainChunk {
+    Foreground(Sprite),
+    Background(Sprite),
+    Terrain(Sprite),
+}
+impl TerrainChunk {
+    fn loc(&self) -> Vector {
+        match self {
+            TerrainChunk::Foreground(s) => s.loc,
+            TerrainChunk::Background(s) => s.loc,
+            TerrainChunk::Terrain(s) => s.loc,
+        }
+    }
+
+    fn pixel_count(&self) -> u32 {
+        match self {
+            TerrainChunk::Foreground(s) => s.width() + s.height(),
+            TerrainChunk::Background(s) => s.width() + s.height(),
+            TerrainChunk::Terrain(s) => s.width() + s.height(),
+        }
+    }
+
+    fn quarter(self) -> Vec<Self> {
+        match self {
+            TerrainChunk::Foreground(s) => s
+                .quarter()
+                .into_iter()
+                .map(|s| TerrainChunk::Foreground(s))
+                .collect(),
+            TerrainChunk::Background(s) => s
+                .quarter()
+                .into_iter()
+                .map(|s| TerrainChunk::Background(s))
+                .collect(),
+            TerrainChunk::Terrain(s) => s
+                .quarter()
+                .into_iter()
+                .map(|s| TerrainChunk::Terrain(s))
+                .collect(),
+        }
+    }
+}
+
+// Sprites keep their source colors when full_color is set on the object, its
+// group or the map, multiplied by an optional tint
+fn full_color_tint(
+    object: &tiled::Properties,
+    group: &tiled::Properties,
+    map: &tiled::Properties,
+) -> Option<Color> {
+    let lookup = |name: &str| {
+        object
+            .get(name)
+            .or_else(|| group.get(name))
+            .or_else(|| map.get(name))
+    };
+    if let Some(tiled::PropertyValue::BoolValue(true)) = lookup("full_color") {
+        if let Some(tiled::PropertyValue::ColorValue(v)) = lookup("tint") {
+            Some(Color::from_rgba(
+                (v >> 16 & 0xff) as u8,
+                (v >> 8 & 0xff) as u8,
+                (v & 0xff) as u8,
+                (v >> 24 & 0xff) as f32 / 255.0,
+            ))
+        } else {
+            Some(Color::WHITE)
+        }
+    } else {
+        None
+    }
+}
+
+// Animations are lists of tile gids like anim_walk = "12,13,14,13"
+fn animator(
+    properties: &tiled::Properties,
+    tilesets: &Tilesets,
+    tint: Option<Color>,
+) -> Option<Animator> {
+    let mut animations = HashMap::default();
+    let fps = if let Some(v) = properties.get("anim_fps") {
+        match v {
+            tiled::PropertyValue::FloatValue(v) => *v,
+            tiled::PropertyValue::IntValue(v) => *v as f32,
+            _ => 8.0,
+        }
+    } else {
+        8.0
+    };
+    for (state, name) in &[
+        (AnimState::Idle, "anim_idle"),
+        (AnimState::Walk, "anim_walk"),
+        (AnimState::Jump, "anim_jump"),
+        (AnimState::Fall, "anim_fall"),
+        (AnimState::Drink, "anim_drink"),
+    ] {
+        if let Some(tiled::PropertyValue::StringValue(v)) = properties.get(*name) {
+            let frames: Vec<_> = v
+                .split(',')
+                .filter_map(|gid| gid.trim().parse::<u32>().ok())
+                .map(|gid| TileFlags::from_gid(gid).1)
+                .filter(|gid| *gid > 0)
+                .filter_map(|gid| tilesets.tile(gid))
+                .map(|tile| match tint {
+                    Some(tint) => tile.tinted(tint),
+                    None => tile.flat(),
+                })
+                .collect();
+            if !frames.is_empty() {
+                animations.insert(*state, Animation { frames, fps });
+            }
+        }
+    }
+    if animations.is_empty() {
+        return None;
+    }
+    let collider_mode =
+        if let Some(tiled::PropertyValue::StringValue(v)) = properties.get("anim_collider") {
+            match v.as_str() {
+                "frame" => ColliderMode::PerFrame,
+                "animation" => ColliderMode::PerAnimation,
+                _ => ColliderMode::Fixed,
+            }
+        } else {
+            ColliderMode::Fixed
+        };
+    Some(Animator {
+        animations,
+        collider_mode,
+        state: AnimState::Idle,
+        frame: 0,
+        timer: 0.0,
+    })
+}
+
+// 3x5 pixel font for level text, one bit per pixel, rows top to bottom
+fn glyph(c: char) -> u16 {
+    match c.to_ascii_uppercase() {
+        'A' => 0b010_101_111_101_101,
+        'B' => 0b110_101_110_101_110,
+        'C' => 0b011_100_100_100_011,
+        'D' => 0b110_101_101_101_110,
+        'E' => 0b111_100_110_100_111,
+        'F' => 0b111_100_110_100_100,
+        'G' => 0b011_100_101_101_011,
+        'H' => 0b101_101_111_101_101,
+        'I' => 0b111_010_010_010_111,
+        'J' => 0b001_001_001_101_010,
+        'K' => 0b101_101_110_101_101,
+        'L' => 0b100_100_100_100_111,
+        'M' => 0b101_111_111_101_101,
+        'N' => 0b110_101_101_101_101,
+        'O' => 0b010_101_101_101_010,
+        'P' => 0b110_101_110_100_100,
+        'Q' => 0b010_101_101_110_011,
+        'R' => 0b110_101_110_101_101,
+        'S' => 0b011_100_010_001_110,
+        'T' => 0b111_010_010_010_010,
+        'U' => 0b101_101_101_101_111,
+        'V' => 0b101_101_101_101_010,
+        'W' => 0b101_101_111_111_101,
+        'X' => 0b101_101_010_101_101,
+        'Y' => 0b101_101_010_010_010,
+        'Z' => 0b111_001_010_100_111,
+        '0' => 0b111_101_101_101_111,
+        '1' => 0b010_110_010_010_111,
+        '2' => 0b110_001_010_100_111,
+        '3' => 0b110_001_010_001_110,
+        '4' => 0b101_101_111_001_001,
+        '5' => 0b111_100_110_001_110,
+        '6' => 0b011_100_111_101_111,
+        '7' => 0b111_001_010_010_010,
+        '8' => 0b111_101_111_101_111,
+        '9' => 0b111_101_111_001_110,
+        '!' => 0b010_010_010_000_010,
+        '?' => 0b110_001_010_000_010,
+        '.' => 0b000_000_000_000_010,
+        ',' => 0b000_000_000_010_100,
+        '\'' => 0b010_010_000_000_000,
+        '-' => 0b000_000_111_000_000,
+        ':' => 0b000_010_000_010_000,
+        _ => 0,
+    }
+}
+
+fn draw_text(gfx: &mut Graphics, text: &str, center: Vector, size: f32, color: Color) {
+    let width = text.chars().count() as f32 * 4.0 * size - size;
+    let left = center.x - width / 2.0;
+    let top = center.y - 2.5 * size;
+    for (i, c) in text.chars().enumerate() {
+        let bits = glyph(c);
+        for row in 0..5 {
+            for column in 0..3 {
+                if bits & (1 << (14 - row * 3 - column)) != 0 {
+                    let x = left + (i as f32 * 4.0 + column as f32) * size;
+                    let y = top + row as f32 * size;
+                    gfx.fill_rect(
+                        &Rectangle::new(Vector::new(x, y), Vector::new(size, size)),
+                        color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Tile objects hang up from their y, plain rectangles hang down
+fn object_area(object: &tiled::Object) -> vek::geom::Rect<f32, f32> {
+    let top = if object.gid == 0 {
+        object.y
+    } else {
+        object.y - object.height
+    };
+    vek::geom::Rect::new(object.x, top, object.width, object.height)
 }
-impl TerrainChunk {
-    fn loc(&self) -> Vector {
-        match self {
-            TerrainChunk::Foreground(s) => s.loc,
-            TerrainChunk::Background(s) => s.loc,
-            TerrainChunk::Terrain(s) => s.loc,
-        }
+
+// Points are written as "x,y"
+fn point_property(properties: &tiled::Properties, name: &str) -> Option<Vector> {
+    if let Some(tiled::PropertyValue::StringValue(v)) = properties.get(name) {
+        parse_point(v)
+    } else {
+        None
     }
+}
 
-    fn pixel_count(&self) -> u32 {
-        match self {
-            TerrainChunk::Foreground(s) => {
-                s.x_scale * SPRITE_WIDTH as u32 + s.y_scale * SPRITE_WIDTH as u32
-            }
-            TerrainChunk::Background(s) => {
-                s.x_scale * SPRITE_WIDTH as u32 + s.y_scale * SPRITE_WIDTH as u32
-            }
-            TerrainChunk::Terrain(s) => {
-                s.x_scale * SPRITE_WIDTH as u32 + s.y_scale * SPRITE_WIDTH as u32
+// min_scale and max_scale apply to both axes, min_x_scale and friends
+// override a single axis
+fn scale_limits(properties: &tiled::Properties) -> ScaleLimits {
+    let float = |name: &str, default: f32| {
+        if let Some(v) = properties.get(name) {
+            match v {
+                tiled::PropertyValue::FloatValue(v) => *v,
+                tiled::PropertyValue::IntValue(v) => *v as f32,
+                _ => default,
             }
+        } else {
+            default
         }
+    };
+    let min = float("min_scale", MIN_SCALE);
+    let max = float("max_scale", MAX_SCALE);
+    // Each axis is checked on its own since clamp panics when max is below min
+    let axis = |min_name: &str, max_name: &str| {
+        let min = float(min_name, min).max(0.01);
+        (min, float(max_name, max).max(min))
+    };
+    let (min_x, max_x) = axis("min_x_scale", "max_x_scale");
+    let (min_y, max_y) = axis("min_y_scale", "max_y_scale");
+    ScaleLimits {
+        min: (min_x, min_y),
+        max: (max_x, max_y),
     }
+}
 
-    fn quarter(self) -> Vec<Self> {
-        match self {
-            TerrainChunk::Foreground(s) => s
-                .quarter()
-                .into_iter()
-                .map(|s| TerrainChunk::Foreground(s))
-                .collect(),
-            TerrainChunk::Background(s) => s
-                .quarter()
-                .into_iter()
-                .map(|s| TerrainChunk::Background(s))
-                .collect(),
-            TerrainChunk::Terrain(s) => s
-                .quarter()
-                .into_iter()
-                .map(|s| TerrainChunk::Terrain(s))
-                .collect(),
+fn destruction_profile(properties: &tiled::Properties) -> DestructionProfile {
+    let float = |name: &str, default: f32| {
+        if let Some(v) = properties.get(name) {
+            match v {
+                tiled::PropertyValue::FloatValue(v) => *v,
+                tiled::PropertyValue::IntValue(v) => *v as f32,
+                _ => default,
+            }
+        } else {
+            default
         }
+    };
+    let shape = if let Some(tiled::PropertyValue::StringValue(v)) = properties.get("destruction") {
+        match v.as_str() {
+            "cone" => DestructionShape::Cone {
+                angle: float("cone_angle", -90.0).to_radians(),
+                spread: float("cone_spread", 30.0).to_radians(),
+            },
+            "footprint" => DestructionShape::Footprint,
+            "column" => DestructionShape::Column,
+            "ground_pound" => DestructionShape::GroundPound {
+                depth: float("pound_depth", 0.5),
+            },
+            _ => DestructionShape::Circle,
+        }
+    } else {
+        DestructionShape::Circle
+    };
+    DestructionProfile {
+        shape,
+        strength: float("destruction_strength", 1.0),
+        falloff: float("destruction_falloff", 0.0).clamp(0.0, 1.0),
     }
 }
 
-async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()> {
-    let sprites = image::load(std::io::Cursor::new(SPRITES), image::ImageFormat::Png).unwrap();
-    //let map_data = include_bytes!("../static/map.tmx").to_vec();//quicksilver::load_file("map.tmx").await.expect("The file was not found!");
-    let map_data = quicksilver::load_file("map.tmx")
+// Everything in one map, ready to play, along with the player and the terrain still to be rasterized
+async fn load_level(path: &str, gfx: &Graphics) -> (Scene, usize, Vec<TerrainChunk>) {
+    let map_data = quicksilver::load_file(path)
         .await
         .expect("The file was not found!");
     let map = tiled::parse(&*map_data).unwrap();
+    let tilesets = Tilesets::load(&map).await;
     let mut scene = Scene::new();
+    if let Some(tiled::PropertyValue::IntValue(v)) = map.properties.get("particle_budget") {
+        scene.particles = ParticlePool::new((*v).max(0) as usize);
+    }
+    scene.scale_limits = scale_limits(&map.properties);
+    scene.max_scale_camera = point_property(&map.properties, "max_scale_camera");
+    for (name, conditions) in &mut [
+        ("win", &mut scene.win_conditions),
+        ("lose", &mut scene.lose_conditions),
+    ] {
+        if let Some(tiled::PropertyValue::StringValue(v)) = map.properties.get(*name) {
+            **conditions = parse_conditions(v);
+        }
+    }
+    for (name, actions) in &mut [
+        ("on_win", &mut scene.on_win),
+        ("on_lose", &mut scene.on_lose),
+    ] {
+        if let Some(tiled::PropertyValue::StringValue(v)) = map.properties.get(*name) {
+            **actions = parse_actions(v);
+        }
+    }
+    for (name, ratio) in &mut [
+        ("stomp_ratio", &mut scene.size_rules.stomp),
+        ("ride_ratio", &mut scene.size_rules.ride),
+        ("slip_ratio", &mut scene.size_rules.slip),
+        ("push_ratio", &mut scene.size_rules.push),
+    ] {
+        match map.properties.get(*name) {
+            Some(tiled::PropertyValue::FloatValue(v)) => **ratio = *v,
+            Some(tiled::PropertyValue::IntValue(v)) => **ratio = *v as f32,
+            _ => (),
+        }
+    }
+    if let Some(v) = map.properties.get("world_floor") {
+        match v {
+            tiled::PropertyValue::FloatValue(v) => scene.world_floor = *v,
+            tiled::PropertyValue::IntValue(v) => scene.world_floor = *v as f32,
+            _ => (),
+        }
+    }
+    if let Some(tiled::PropertyValue::BoolValue(v)) = map.properties.get("falling_sand") {
+        scene.falling_sand = *v;
+    }
+    if let Some(v) = map.properties.get("sand_repose") {
+        match v {
+            tiled::PropertyValue::FloatValue(v) => scene.sand_repose = v.to_radians(),
+            tiled::PropertyValue::IntValue(v) => scene.sand_repose = (*v as f32).to_radians(),
+            _ => (),
+        }
+    }
     let mut player_id = None;
     let mut negative_terrain = vec![];
     let mut terrain_chunks = vec![];
+    let mut platform_paths = HashMap::new();
+    for group in &map.object_groups {
+        if group.visible && group.name == "platforms" {
+            for object in &group.objects {
+                if let tiled::ObjectShape::Polyline { points } = &object.shape {
+                    let path: Vec<_> = points
+                        .iter()
+                        .map(|(x, y)| Vector::new(object.x + x, object.y + y))
+                        .collect();
+                    platform_paths.insert(object.name.clone(), path);
+                }
+            }
+        } else if group.visible && group.name == "exits" {
+            for object in &group.objects {
+                let id = scene.add_trigger(object_area(object));
+                let exit = if let Some(tiled::PropertyValue::StringValue(v)) =
+                    object.properties.get("level")
+                {
+                    LevelExit::Map(v.clone())
+                } else {
+                    LevelExit::Next
+                };
+                scene.listen(
+                    SceneEvent::EnteredZone(id),
+                    vec![Action::ExitLevel(exit)],
+                    true,
+                );
+            }
+        } else if group.visible
+            && (group.name == "spikes" || group.name == "water" || group.name == "kill")
+        {
+            for object in &group.objects {
+                let kind = match group.name.as_str() {
+                    "spikes" => HazardKind::Spikes {
+                        safe_scale: match object.properties.get("safe_scale") {
+                            Some(tiled::PropertyValue::FloatValue(v)) => *v,
+                            Some(tiled::PropertyValue::IntValue(v)) => *v as f32,
+                            _ => f32::INFINITY,
+                        },
+                    },
+                    "water" => HazardKind::Water {
+                        breath: match object.properties.get("breath") {
+                            Some(tiled::PropertyValue::FloatValue(v)) => *v,
+                            Some(tiled::PropertyValue::IntValue(v)) => *v as f32,
+                            _ => BREATH,
+                        },
+                    },
+                    _ => HazardKind::Kill,
+                };
+                scene.hazards.push(Hazard {
+                    area: object_area(object),
+                    kind,
+                });
+            }
+        } else if group.visible && group.name == "checkpoints" {
+            for object in &group.objects {
+                let id = scene.add_trigger(object_area(object));
+                scene.listen(SceneEvent::EnteredZone(id), vec![Action::Checkpoint], true);
+            }
+        } else if group.visible && group.name == "triggers" {
+            for object in &group.objects {
+                let id = scene.add_trigger(object_area(object));
+                let once = if let Some(tiled::PropertyValue::BoolValue(v)) =
+                    object.properties.get("once")
+                {
+                    *v
+                } else {
+                    false
+                };
+                for (name, event) in &[
+                    ("on_enter", SceneEvent::EnteredZone(id)),
+                    ("on_exit", SceneEvent::ExitedZone(id)),
+                ] {
+                    if let Some(tiled::PropertyValue::StringValue(v)) = object.properties.get(*name)
+                    {
+                        scene.listen(*event, parse_actions(v), once);
+                    }
+                }
+                if let Some(tiled::PropertyValue::StringValue(v)) = object.properties.get("script")
+                {
+                    scene.attach_script(
+                        v,
+                        None,
+                        &[
+                            ("on_enter", SceneEvent::EnteredZone(id)),
+                            ("on_exit", SceneEvent::ExitedZone(id)),
+                        ],
+                        once,
+                    );
+                }
+            }
+        }
+    }
     for group in &map.object_groups {
         if !group.visible {
             continue;
         }
         for object in &group.objects {
-            let x_scale = (object.width / 16.0) as u32;
-            let y_scale = (object.height / 16.0) as u32;
-            assert_eq!(
-                x_scale as f32 * 16.0,
-                object.width,
-                "badly scaled sprite {} in {}",
-                object.id,
-                group.name
-            );
-            assert_eq!(y_scale as f32 * 16.0, object.height);
-            let flipped = object.gid & 0x80000000 != 0;
-            let gid = object.gid & !0x80000000;
-            let ty = (gid - 1) / 48;
-            let tx = (gid - 1) - ty as u32 * 48;
+            if object.gid == 0 {
+                continue;
+            }
+            let (flipped, gid) = TileFlags::from_gid(object.gid);
+            let (tile, (tile_width, tile_height)) =
+                match (tilesets.tile(gid), tilesets.tile_size(gid)) {
+                    (Some(tile), Some(size)) => (tile, size),
+                    _ => continue,
+                };
+            let x_scale = object.width / tile_width as f32;
+            let y_scale = object.height / tile_height as f32;
+            let tint = full_color_tint(&object.properties, &group.properties, &map.properties);
+            let tile = match tint {
+                Some(tint) => tile.tinted(tint),
+                None => tile.flat(),
+            };
             let gravity = if let Some(v) = object.properties.get("gravity") {
                 match v {
                     tiled::PropertyValue::BoolValue(v) => *v,
@@ -1458,6 +4819,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 true
             };
 
+            let first_id = scene.next_id;
             let preload = if let Some(v) = object.properties.get("preload") {
                 match v {
                     tiled::PropertyValue::BoolValue(v) => *v,
@@ -1468,21 +4830,75 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
             };
 
             if group.name == "player" || group.name == "test_player" {
-                player_id = Some(scene.add_character(Sprite::new(
-                    &sprites,
-                    tx as usize,
-                    ty as usize,
+                let mut player = Sprite::new(
+                    &tile,
                     object.x,
                     object.y - object.height,
                     x_scale,
                     y_scale,
                     Color::BLUE,
-                )));
+                )
+                .maybe_flip(flipped);
+                player.animator = animator(&object.properties, &tilesets, tint);
+                player_id = Some(scene.add_character(player));
+            } else if group.name == "npcs" {
+                let behavior = if let Some(tiled::PropertyValue::StringValue(v)) =
+                    object.properties.get("behavior")
+                {
+                    match v.as_str() {
+                        "chase" => Behavior::Chase,
+                        "flee" => Behavior::Flee,
+                        "sleep" => Behavior::Sleep,
+                        _ => Behavior::Patrol,
+                    }
+                } else {
+                    Behavior::Patrol
+                };
+                let speed = if let Some(v) = object.properties.get("speed") {
+                    match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => 40.0,
+                    }
+                } else {
+                    40.0
+                };
+                let range = if let Some(v) = object.properties.get("range") {
+                    match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => 800.0,
+                    }
+                } else {
+                    800.0
+                };
+                let mut npc = Sprite::new(
+                    &tile,
+                    object.x,
+                    object.y - object.height,
+                    x_scale,
+                    y_scale,
+                    Color::GREEN,
+                )
+                .maybe_flip(flipped);
+                npc.animator = animator(&object.properties, &tilesets, tint);
+                if !gravity {
+                    npc.gravity = false;
+                }
+                let direction = if npc.facing_left { -1.0 } else { 1.0 };
+                scene.add_npc(
+                    npc,
+                    Npc {
+                        behavior,
+                        speed,
+                        range,
+                        origin: object.x,
+                        direction,
+                    },
+                );
             } else if group.name == "collectable" {
                 let mut collectable = Sprite::new(
-                    &sprites,
-                    tx as usize,
-                    ty as usize,
+                    &tile,
                     object.x,
                     object.y - object.height,
                     x_scale,
@@ -1500,7 +4916,8 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 {
                     let x_absolute = if let Some(v) = object.properties.get("x_absolute") {
                         match v {
-                            tiled::PropertyValue::IntValue(v) => Some(*v),
+                            tiled::PropertyValue::FloatValue(v) => Some(*v),
+                            tiled::PropertyValue::IntValue(v) => Some(*v as f32),
                             _ => None,
                         }
                     } else {
@@ -1508,49 +4925,147 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                     };
                     let y_absolute = if let Some(v) = object.properties.get("y_absolute") {
                         match v {
-                            tiled::PropertyValue::IntValue(v) => Some(*v),
+                            tiled::PropertyValue::FloatValue(v) => Some(*v),
+                            tiled::PropertyValue::IntValue(v) => Some(*v as f32),
                             _ => None,
                         }
                     } else {
                         None
                     };
                     (PotionType::Absolute(x_absolute, y_absolute), Color::RED)
+                } else if object.properties.contains_key("x_multiply")
+                    || object.properties.contains_key("y_multiply")
+                {
+                    let x_multiply = if let Some(v) = object.properties.get("x_multiply") {
+                        match v {
+                            tiled::PropertyValue::FloatValue(v) => *v,
+                            tiled::PropertyValue::IntValue(v) => *v as f32,
+                            _ => 1.0,
+                        }
+                    } else {
+                        1.0
+                    };
+                    let y_multiply = if let Some(v) = object.properties.get("y_multiply") {
+                        match v {
+                            tiled::PropertyValue::FloatValue(v) => *v,
+                            tiled::PropertyValue::IntValue(v) => *v as f32,
+                            _ => 1.0,
+                        }
+                    } else {
+                        1.0
+                    };
+                    let color = if x_multiply * y_multiply >= 1.0 {
+                        Color::RED
+                    } else {
+                        Color::BLUE
+                    };
+                    (PotionType::Multiplicative(x_multiply, y_multiply), color)
+                } else if let Some(v) = object.properties.get("gravity_multiply") {
+                    let g = match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => 1.0,
+                    };
+                    // Zero can't be reverted, so clamp away from it
+                    let g = if g.abs() < 0.01 {
+                        0.01f32.copysign(g)
+                    } else {
+                        g
+                    };
+                    (PotionType::Gravity(g), Color::CYAN)
+                } else if let Some(v) = object.properties.get("jump_multiply") {
+                    let j = match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => 1.0,
+                    };
+                    let j = if j.abs() < 0.01 {
+                        0.01f32.copysign(j)
+                    } else {
+                        j
+                    };
+                    (PotionType::Jump(j), Color::MAGENTA)
+                } else if let Some(v) = object.properties.get("stretch") {
+                    let k = match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => 1.0,
+                    };
+                    let k = k.max(0.01);
+                    let color = if k >= 1.0 {
+                        Color::YELLOW
+                    } else {
+                        Color::PURPLE
+                    };
+                    (PotionType::Stretch(k), color)
                 } else {
                     let x_relative = if let Some(v) = object.properties.get("x_delta") {
                         match v {
-                            tiled::PropertyValue::IntValue(v) => *v,
-                            _ => 1,
+                            tiled::PropertyValue::FloatValue(v) => *v,
+                            tiled::PropertyValue::IntValue(v) => *v as f32,
+                            _ => 1.0,
                         }
                     } else {
-                        1
+                        1.0
                     };
                     let y_relative = if let Some(v) = object.properties.get("y_delta") {
                         match v {
-                            tiled::PropertyValue::IntValue(v) => *v,
-                            _ => 1,
+                            tiled::PropertyValue::FloatValue(v) => *v,
+                            tiled::PropertyValue::IntValue(v) => *v as f32,
+                            _ => 1.0,
                         }
                     } else {
-                        1
+                        1.0
                     };
-                    let color = if x_relative + y_relative > 0 {
+                    let color = if x_relative + y_relative > 0.0 {
                         Color::RED
                     } else {
                         Color::BLUE
                     };
                     (PotionType::Relative(x_relative, y_relative), color)
                 };
-                let start_end = if let Some(v) = object.properties.get("start_end") {
+                let duration = if let Some(v) = object.properties.get("duration") {
                     match v {
-                        tiled::PropertyValue::BoolValue(v) => *v,
-                        _ => false,
+                        tiled::PropertyValue::FloatValue(v) => Some(*v),
+                        tiled::PropertyValue::IntValue(v) => Some(*v as f32),
+                        _ => None,
                     }
                 } else {
-                    false
+                    None
+                };
+                let period = if let Some(v) = object.properties.get("oscillate") {
+                    match v {
+                        tiled::PropertyValue::FloatValue(v) => Some(*v),
+                        tiled::PropertyValue::IntValue(v) => Some(*v as f32),
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+                let anchor = if let Some(tiled::PropertyValue::StringValue(v)) =
+                    object.properties.get("anchor")
+                {
+                    match v.as_str() {
+                        "center" => ScaleAnchor::Center,
+                        "head" => ScaleAnchor::Head,
+                        _ => ScaleAnchor::Feet,
+                    }
+                } else {
+                    ScaleAnchor::Feet
+                };
+                let growth = if let Some(tiled::PropertyValue::StringValue(v)) =
+                    object.properties.get("growth")
+                {
+                    match v.as_str() {
+                        "block" => GrowthPolicy::Block,
+                        "squeeze" => GrowthPolicy::Squeeze,
+                        _ => GrowthPolicy::Smash,
+                    }
+                } else {
+                    GrowthPolicy::Smash
                 };
                 let mut potion = Sprite::new(
-                    &sprites,
-                    tx as usize,
-                    ty as usize,
+                    &tile,
                     object.x,
                     object.y - object.height,
                     x_scale,
@@ -1560,14 +5075,140 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 if !gravity {
                     potion.gravity = false;
                 }
-                scene.add_potion(potion, potion_type, start_end);
+                scene.add_potion(
+                    potion,
+                    Potion {
+                        effect: potion_type,
+                        anchor,
+                        growth,
+                        destruction: destruction_profile(&object.properties),
+                        duration,
+                        period,
+                    },
+                );
+            } else if group.name == "hazards" {
+                let fuse = if let Some(v) = object.properties.get("fuse") {
+                    match v {
+                        tiled::PropertyValue::FloatValue(v) => Some(*v),
+                        tiled::PropertyValue::IntValue(v) => Some(*v as f32),
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+                let lit = if let Some(tiled::PropertyValue::BoolValue(v)) =
+                    object.properties.get("lit")
+                {
+                    *v
+                } else {
+                    false
+                };
+                let contact = if let Some(tiled::PropertyValue::BoolValue(v)) =
+                    object.properties.get("contact")
+                {
+                    *v
+                } else {
+                    true
+                };
+                let force = if let Some(v) = object.properties.get("force") {
+                    match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => 2.0,
+                    }
+                } else {
+                    2.0
+                };
+                let mut profile = destruction_profile(&object.properties);
+                if !object.properties.contains_key("destruction_strength") {
+                    profile.strength = 3.0;
+                }
+                let mut bomb = Sprite::new(
+                    &tile,
+                    object.x,
+                    object.y - object.height,
+                    x_scale,
+                    y_scale,
+                    Color::ORANGE,
+                )
+                .maybe_flip(flipped);
+                if !gravity {
+                    bomb.gravity = false;
+                }
+                scene.add_bomb(
+                    bomb,
+                    Bomb {
+                        fuse,
+                        lit,
+                        contact,
+                        force,
+                        profile,
+                    },
+                );
+            } else if group.name == "platforms" {
+                let start = Vector::new(object.x, object.y);
+                let mut path = vec![start];
+                if let Some(tiled::PropertyValue::StringValue(name)) = object.properties.get("path")
+                {
+                    if let Some(points) = platform_paths.get(name) {
+                        path = points.clone();
+                    }
+                }
+                let speed = if let Some(v) = object.properties.get("speed") {
+                    match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => 60.0,
+                    }
+                } else {
+                    60.0
+                };
+                let mode = if let Some(tiled::PropertyValue::StringValue(v)) =
+                    object.properties.get("mode")
+                {
+                    if v == "ping-pong" || v == "pingpong" {
+                        PathMode::PingPong
+                    } else {
+                        PathMode::Loop
+                    }
+                } else {
+                    PathMode::Loop
+                };
+                let platform = Sprite::new(
+                    &tile,
+                    path[0].x,
+                    path[0].y - object.height,
+                    x_scale,
+                    y_scale,
+                    TERRAIN_COLOR,
+                )
+                .maybe_flip(flipped);
+                scene.add_platform(platform, Platform::new(path, speed, mode));
             } else if group.name.starts_with("terrain") {
+                let hardness = object
+                    .properties
+                    .get("hardness")
+                    .or_else(|| group.properties.get("hardness"));
+                if let Some(v) = hardness {
+                    let hardness = match v {
+                        tiled::PropertyValue::FloatValue(v) => *v,
+                        tiled::PropertyValue::IntValue(v) => *v as f32,
+                        _ => DEFAULT_HARDNESS,
+                    };
+                    scene.materials.push((
+                        vek::geom::Rect::new(
+                            object.x as i32,
+                            (object.y - object.height) as i32,
+                            object.width as i32,
+                            object.height as i32,
+                        ),
+                        hardness,
+                    ));
+                }
                 if preload {
                     scene.add_terrain(
                         &Sprite::new(
-                            &sprites,
-                            tx as usize,
-                            ty as usize,
+                            &tile,
                             object.x,
                             object.y - object.height,
                             x_scale,
@@ -1579,9 +5220,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 } else {
                     terrain_chunks.push(TerrainChunk::Terrain(
                         Sprite::new(
-                            &sprites,
-                            tx as usize,
-                            ty as usize,
+                            &tile,
                             object.x,
                             object.y - object.height,
                             x_scale,
@@ -1595,9 +5234,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 //terrain_locations.insert((object.x as i32 / TILE_SIZE as i32, (object.y - object.height) as i32 / TILE_SIZE as i32));
                 negative_terrain.push(
                     Sprite::new(
-                        &sprites,
-                        tx as usize,
-                        ty as usize,
+                        &tile,
                         object.x,
                         object.y - object.height,
                         x_scale,
@@ -1610,9 +5247,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 if preload {
                     scene.add_background(
                         &Sprite::new(
-                            &sprites,
-                            tx as usize,
-                            ty as usize,
+                            &tile,
                             object.x,
                             object.y - object.height,
                             x_scale,
@@ -1624,9 +5259,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 } else {
                     terrain_chunks.push(TerrainChunk::Background(
                         Sprite::new(
-                            &sprites,
-                            tx as usize,
-                            ty as usize,
+                            &tile,
                             object.x,
                             object.y - object.height,
                             x_scale,
@@ -1640,9 +5273,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 if preload {
                     scene.add_foreground(
                         &Sprite::new(
-                            &sprites,
-                            tx as usize,
-                            ty as usize,
+                            &tile,
                             object.x,
                             object.y - object.height,
                             x_scale,
@@ -1654,9 +5285,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 } else {
                     terrain_chunks.push(TerrainChunk::Foreground(
                         Sprite::new(
-                            &sprites,
-                            tx as usize,
-                            ty as usize,
+                            &tile,
                             object.x,
                             object.y - object.height,
                             x_scale,
@@ -1667,14 +5296,49 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                     ));
                 }
             }
+            for sprite_id in first_id..scene.next_id {
+                for (name, event) in &[
+                    ("on_drink", SceneEvent::PotionDrunk(sprite_id)),
+                    ("on_collect", SceneEvent::CollectablePicked(sprite_id)),
+                    ("on_destroy", SceneEvent::SpriteDestroyed(sprite_id)),
+                ] {
+                    if let Some(tiled::PropertyValue::StringValue(v)) = object.properties.get(*name)
+                    {
+                        scene.listen(*event, parse_actions(v), true);
+                    }
+                }
+                if let Some(tiled::PropertyValue::StringValue(v)) = object.properties.get("script")
+                {
+                    scene.attach_script(
+                        v,
+                        Some(sprite_id),
+                        &[
+                            ("on_drink", SceneEvent::PotionDrunk(sprite_id)),
+                            ("on_collect", SceneEvent::CollectablePicked(sprite_id)),
+                            ("on_destroy", SceneEvent::SpriteDestroyed(sprite_id)),
+                        ],
+                        true,
+                    );
+                }
+                if let Some(tiled::PropertyValue::StringValue(name)) =
+                    object.properties.get("spawned_by")
+                {
+                    scene.park(sprite_id, name.clone());
+                }
+            }
         }
     }
-    let mut terrain_chunks: Vec<_> = terrain_chunks
+    let terrain_chunks: Vec<_> = terrain_chunks
         .into_iter()
         .flat_map(|c| {
             let mut result = vec![c];
             while result[0].pixel_count() > 160 * 160 {
+                let count = result.len();
                 result = result.into_iter().flat_map(|c| c.quarter()).collect();
+                // Chunks too thin to split stay as big as they are
+                if result.len() == count {
+                    break;
+                }
             }
             result
         })
@@ -1684,17 +5348,67 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
     }
 
     for (sprite_id, sprite) in &scene.sprites {
-        scene.sprite_cache.insert(*sprite_id, sprite.image(&gfx));
+        scene.sprite_cache.insert(*sprite_id, sprite.image(gfx));
     }
 
     let player_id = player_id.unwrap();
-
-    let mut setup_end = false;
-
     let player = scene.sprites.get_mut(&player_id).unwrap();
     player.is_player = true;
+    scene.spawn = player.loc;
+    scene.spawn_scale = (player.x_scale, player.y_scale);
+    scene.level_sprites = scene.next_id;
+    (scene, player_id, terrain_chunks)
+}
+
+// The maps to play in order, one per line of levels.txt
+async fn level_manifest() -> Vec<String> {
+    let levels: Vec<String> = match quicksilver::load_file("levels.txt").await {
+        Ok(data) => String::from_utf8_lossy(&data)
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.to_string())
+            .collect(),
+        Err(_) => vec![],
+    };
+    if levels.is_empty() {
+        vec!["map.tmx".to_string()]
+    } else {
+        levels
+    }
+}
+
+async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()> {
+    let levels = level_manifest().await;
+    let (movement, movement_error) = movement_profile().await;
+    // Levels are numbered from one on the command line, picking one starts it fresh
+    let start = std::env::args()
+        .skip_while(|a| a != "--level")
+        .nth(1)
+        .and_then(|n| n.parse::<usize>().ok())
+        .map(|n| n.clamp(1, levels.len()) - 1);
+    let save = if start.is_none() {
+        quicksilver::saving::load::<SaveGame>(Location::Data, SAVE_NAME, "save")
+            .ok()
+            .filter(|save| save.level < levels.len())
+    } else {
+        None
+    };
+    let mut level = start
+        .or_else(|| save.as_ref().map(|s| s.level))
+        .unwrap_or(0);
+    let (mut scene, mut player_id, mut terrain_chunks) = load_level(&levels[level], &gfx).await;
+    scene.movement = movement.clone();
+    if let Some(save) = &save {
+        scene.restore(save, &mut terrain_chunks);
+    }
+    if let Some(error) = movement_error {
+        scene.message = Some((error, 5.0));
+    }
+
+    let player = &scene.sprites[&player_id];
     let mut camera = player.loc;
-    let mut camera_scale = player.x_scale.max(player.y_scale) as f32;
+    let mut camera_scale = player.framing_scale();
 
     let mut fps = 60.0;
 
@@ -1703,7 +5417,10 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
     let mut moving_left = false;
     let mut moving_right = false;
 
-    let mut step_cache_warmer = |scene: &mut Scene, _gfx: &mut Graphics, camera_scale: f32| {
+    let step_cache_warmer = |scene: &mut Scene,
+                             terrain_chunks: &mut Vec<TerrainChunk>,
+                             player_id: usize,
+                             camera_scale: f32| {
         let mut did_work = false;
         if !terrain_chunks.is_empty() {
             let player_loc = scene.sprites[&player_id].loc;
@@ -1763,6 +5480,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                     1 => (&mut scene.collision_map, TERRAIN_COLOR),
                     _ => (&mut scene.foreground_map, FOREGROUND_COLOR),
                 };
+                let baked = scene.tile_colors.get(&(layer, x, y));
                 let tile =
                     e.0.get_or_insert_with(|| vec![0; (TILE_SIZE * TILE_SIZE * 4) as usize]);
                 for dx in 0..TILE_SIZE {
@@ -1772,6 +5490,12 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                             y * TILE_SIZE as i32 + dy as i32,
                         ) {
                             let i = (dx + dy * TILE_SIZE) as usize * 4;
+                            if let Some(baked) = baked {
+                                if baked[i + 3] > 0 {
+                                    tile[i..i + 4].copy_from_slice(&baked[i..i + 4]);
+                                    continue;
+                                }
+                            }
                             tile[i] = (color.r * 255.0).clamp(0.0, 255.0) as u8;
                             tile[i + 1] = (color.g * 255.0).clamp(0.0, 255.0) as u8;
                             tile[i + 2] = (color.b * 255.0).clamp(0.0, 255.0) as u8;
@@ -1815,7 +5539,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                         if e.is_down() {
                             if player.ground_contact && !paused {
                                 player.jumping = true;
-                                player.velocity.y = -80.0 / fps;
+                                player.velocity.y = player.jump_velocity(&scene.movement, fps);
                             }
                         }
                     }
@@ -1864,11 +5588,13 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                         if e.is_down() {
                             if player.ground_contact && !paused {
                                 player.jumping = true;
-                                player.velocity.y = -80.0 / fps;
+                                player.velocity.y = player.jump_velocity(&scene.movement, fps);
                             }
                         } else {
                             if !player.ground_contact && player.jumping {
-                                player.velocity.y = player.velocity.y.max(-2.0);
+                                let release = scene.movement.jump_release.at(player.size()) / fps
+                                    * player.fall_scale();
+                                player.velocity.y = player.velocity.y.max(-release);
                             }
                         }
                     }
@@ -1884,14 +5610,14 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
         {
             let player = scene.sprites.get_mut(&player_id).unwrap();
             let vx = if input.key_down(Key::LShift) && player.ground_contact {
-                130.0
+                scene.movement.run_speed.at(player.size())
             } else {
-                60.0
+                scene.movement.walk_speed.at(player.size())
             };
             if moving_right {
-                player.velocity.x = vx / fps;
+                player.velocity.x = player.walk_velocity(vx, fps);
             } else if moving_left {
-                player.velocity.x = -vx / fps;
+                player.velocity.x = -player.walk_velocity(vx, fps);
             } else {
                 player.velocity.x = 0.0;
             }
@@ -1909,49 +5635,70 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 fps = 60.0;
             }
             scene.step_physics(player_loc, camera_scale, fps);
-            if scene.done && !setup_end {
-                setup_end = true;
-                scene.sprites.retain(|i, _| *i == player_id);
-                scene.particles.clear();
-                scene.collectables.clear();
-                scene.potions.clear();
-                scene.sprites.get_mut(&player_id).unwrap().loc = Vector::new(10000.0, 30000.0);
-                for (i, mut collectable) in scene.collected.drain(..) {
-                    collectable.gravity = false;
-                    collectable.velocity = Vector::new(0.0, 0.0);
-                    if collectable.x_scale < 30 {
-                        collectable.x_scale = 50;
-                        collectable.y_scale = 50;
-                        let x = (i as f32 * 1000.0 + camera.x).sin() * 2000.0 + 4000.0;
-                        let y = (i as f32 * 3000.0 + camera.y).sin() * 2000.0 + 4000.0;
-                        collectable.loc = Vector::new(x, y);
-                    }
-                    scene.sprites.insert(i, collectable);
+        }
+        if let Some(exit) = scene.exit.take() {
+            let next = match exit {
+                LevelExit::Next => levels.get(level + 1).map(|path| (level + 1, path.clone())),
+                LevelExit::Map(path) => Some((
+                    levels.iter().position(|l| *l == path).unwrap_or(level),
+                    path,
+                )),
+            };
+            if let Some((next, path)) = next {
+                level = next;
+                let (mut next_scene, next_player_id, next_chunks) = load_level(&path, &gfx).await;
+                next_scene.carry_over(scene);
+                next_scene.movement = movement.clone();
+                scene = next_scene;
+                player_id = next_player_id;
+                terrain_chunks = next_chunks;
+                let player = &scene.sprites[&player_id];
+                camera = player.loc;
+                camera_scale = player.framing_scale();
+                scene.save_requested = true;
+            }
+        }
+        if scene.save_requested {
+            scene.save_requested = false;
+            if let Some(save) = scene.save(level) {
+                if quicksilver::saving::save(Location::Data, SAVE_NAME, "save", &save).is_err() {
+                    scene.message = Some(("Couldn't save".to_string(), 2.0));
                 }
-                scene.collision_map.clear();
-                scene.foreground_map.clear();
-                scene.background_map.clear();
-                scene.tile_cache.clear();
             }
         }
-        step_cache_warmer(&mut scene, &mut gfx, camera_scale);
+        step_cache_warmer(&mut scene, &mut terrain_chunks, player_id, camera_scale);
         if draw_timer.exhaust().is_some() {
             let player = scene.sprites.get_mut(&player_id).unwrap();
-            if player.y_scale < MAX_SCALE as u32 && !scene.done {
-                if camera.distance(player.loc) > player.x_scale.max(player.y_scale) as f32 * 10.0 {
+            let fixed_camera = if let Some((target, _)) = scene.camera_override {
+                Some(target)
+            } else if scene.scale_limits.at_max(player) {
+                scene.max_scale_camera
+            } else {
+                None
+            };
+            if let Some(target) = fixed_camera {
+                camera.x = camera.x * 0.9 + target.x * 0.1;
+                camera.y = camera.y * 0.9 + target.y * 0.1;
+            } else {
+                // The screen is wider than it is tall so allow more slack sideways
+                let framing = player.framing_scale();
+                let slack = Vector::new(
+                    framing * 10.0 * SCREEN_WIDTH / SCREEN_HEIGHT,
+                    framing * 10.0,
+                );
+                if (camera.x - player.loc.x).abs() > slack.x
+                    || (camera.y - player.loc.y).abs() > slack.y
+                {
                     camera.x = camera.x * 0.9 + (player.loc.x) * 0.1;
                     camera.y = camera.y * 0.9 + (player.loc.y) * 0.1;
                 }
-            } else {
-                camera.x = camera.x * 0.9 + 5293.0 * 0.1;
-                camera.y = camera.y * 0.9 + 5429.0 * 0.1;
             }
-            if (camera_scale - player.x_scale.max(player.y_scale) as f32).abs() / camera_scale > 0.1
-            {
-                camera_scale = camera_scale * 0.9 + player.x_scale.max(player.y_scale) as f32 * 0.1;
+            let framing = player.framing_scale();
+            if (camera_scale - framing).abs() / camera_scale > 0.1 {
+                camera_scale = camera_scale * 0.9 + framing * 0.1;
             }
-            if scene.done {
-                camera_scale = camera_scale * 0.9 + 100.0 * 0.1;
+            if let Some((_, zoom)) = scene.camera_override {
+                camera_scale = camera_scale * 0.9 + zoom * 0.1;
             }
             gfx.clear(Color::BLACK);
             let scale = if camera_scale > 8.0 {
@@ -1967,9 +5714,12 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
                 1080,
                 scale,
             );
+            if let Some((text, _)) = &scene.message {
+                draw_text(&mut gfx, text, Vector::new(960.0, 120.0), 8.0, Color::WHITE);
+            }
             if paused {
                 gfx.fill_rect(
-                    &Rectangle::new_sized(Vector::new(1920.0, 1080.0)),
+                    &Rectangle::new_sized(Vector::new(SCREEN_WIDTH, SCREEN_HEIGHT)),
                     Color::from_rgba(255, 255, 255, 0.4),
                 );
             }
@@ -1977,3 +5727,81 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> Result<()>
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(x: f32, y: f32, size: usize) -> Sprite {
+        let mut mask = Mask::new(size, size);
+        for dx in 0..size {
+            for dy in 0..size {
+                mask.set(dx, dy, true);
+            }
+        }
+        Sprite::from_collider(mask, x, y, 1.0, 1.0, TERRAIN_COLOR)
+    }
+
+    #[test]
+    fn platform_stops_when_rider_is_pinned() {
+        let mut scene = Scene::new();
+        // A ceiling right above the rider's head
+        scene.collision_map.insert_rect(-32, -32, 96, 42).unwrap();
+        let rider = scene.add_character(block(0.0, 10.0, 16));
+        let path = vec![Vector::new(0.0, 42.0), Vector::new(0.0, 0.0)];
+        let platform = scene.add_platform(
+            block(0.0, 26.0, 16),
+            Platform::new(path, 60.0, PathMode::PingPong),
+        );
+        for _ in 0..10 {
+            scene.step_platforms(60.0);
+        }
+        assert_eq!(scene.sprites[&platform].loc, Vector::new(0.0, 26.0));
+        assert_eq!(scene.sprites[&rider].loc, Vector::new(0.0, 10.0));
+        assert!(!scene.sprite_blocked(&scene.sprites[&rider]));
+        assert!(!scene.sprites[&rider].overlap(&scene.sprites[&platform]));
+    }
+
+    #[test]
+    fn platform_carries_free_rider() {
+        let mut scene = Scene::new();
+        let rider = scene.add_character(block(0.0, 10.0, 16));
+        let path = vec![Vector::new(0.0, 42.0), Vector::new(0.0, 0.0)];
+        let platform = scene.add_platform(
+            block(0.0, 26.0, 16),
+            Platform::new(path, 60.0, PathMode::PingPong),
+        );
+        for _ in 0..10 {
+            scene.step_platforms(60.0);
+        }
+        assert_eq!(scene.sprites[&platform].loc, Vector::new(0.0, 16.0));
+        assert_eq!(scene.sprites[&rider].loc, Vector::new(0.0, 0.0));
+    }
+
+    // How far above its start a sprite of this scale gets when it jumps in open air
+    fn jump_apex(x_scale: f32, y_scale: f32) -> f32 {
+        let mut scene = Scene::new();
+        let mut sprite = block(0.0, 0.0, 16);
+        sprite.x_scale = x_scale;
+        sprite.y_scale = y_scale;
+        sprite.gravity = true;
+        sprite.velocity.y = sprite.jump_velocity(&scene.movement, 60.0);
+        let id = scene.add_character(sprite);
+        let mut apex: f32 = 0.0;
+        for _ in 0..600 {
+            scene.step_physics(Vector::new(0.0, 0.0), 10.0, 60.0);
+            apex = apex.min(scene.sprites[&id].loc.y);
+        }
+        -apex
+    }
+
+    #[test]
+    fn jump_apex_depends_on_size_not_aspect() {
+        let square = jump_apex(4.0, 4.0);
+        assert!(square > 0.0);
+        for (x_scale, y_scale) in &[(4.0, 1.0), (1.0, 4.0), (4.0, 2.5)] {
+            let apex = jump_apex(*x_scale, *y_scale);
+            assert!((apex - square).abs() <= 1.0, "{} vs {}", apex, square);
+        }
+    }
+}